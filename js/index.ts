@@ -1,13 +1,32 @@
-import { UUID, runStandalone, DEFAULT_SERVER_BINARY_IN_PATH } from "./shim";
+import { UUID, RustSnippetOptions, runStandalone, DEFAULT_SERVER_BINARY_IN_PATH } from "./shim";
 
 import type { TwoSlashOptions, TwoSlashReturn } from "@typescript/twoslash";
 import { createSyncFn } from "synckit";
 
-export { UUID, startServer, shutdownServer } from "./shim";
+export {
+  UUID,
+  RustSnippetOptions,
+  ServerLifetime,
+  RustSnippet,
+  BatchOutcome,
+  runBatchWithServer,
+  RustEdit,
+  openSession,
+  editSession,
+  closeSession,
+  startServer,
+  shutdownServer,
+} from "./shim";
 
 export type TwoSlashRustOptions = TwoSlashOptions & {
   twoslashRustServerId?: UUID;
   twoslashServerBinaryPath?: string;
+  /** Options for this snippet. Only used when running with a server. */
+  twoslashRustSnippetOptions?: RustSnippetOptions;
+  /** The server profile to analyze this snippet with. Only used when running with a server. */
+  twoslashRustProfile?: string;
+  /** How long the server may spend on this snippet, instead of its default. */
+  twoslashRustTimeoutMs?: number;
 };
 
 const runAsServerWorkerPath = require.resolve("./run_as_server_worker");
@@ -25,7 +44,20 @@ export function twoslasher(
     // Hopefully this won't hang the thread... unfortunately, we don't always
     // know that it won't.
     const runAsServer = createSyncFn(runAsServerWorkerPath);
-    return runAsServer(code, serverId);
+    const snippetOptions: RustSnippetOptions = {
+      // Offsets are used to index into JavaScript strings.
+      encoding: "utf16",
+      customTags: options.customTags,
+      ...options.twoslashRustSnippetOptions,
+    };
+    return runAsServer(
+      code,
+      serverId,
+      snippetOptions,
+      options.twoslashRustProfile,
+      options.twoslashRustTimeoutMs,
+      serverBinaryPath
+    );
   }
 
   return runStandalone(code, serverBinaryPath);
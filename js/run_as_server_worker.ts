@@ -1,4 +1,6 @@
 import { runAsWorker } from "synckit";
 import { runWithServer } from "./shim";
 
-runAsWorker((code, serverId) => runWithServer(code, serverId));
+runAsWorker((code, serverId, options, profile, timeoutMs, serverBinaryPath) =>
+  runWithServer(code, serverId, options, profile, timeoutMs, serverBinaryPath)
+);
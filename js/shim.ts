@@ -1,157 +1,445 @@
 import * as cp from "child_process";
-import * as fs from "fs";
 import * as net from "net";
-import { Readable, Writable } from "stream";
+import { Writable } from "stream";
+import { promisify } from "util";
 import { v4 as uuidv4 } from "uuid";
-import { lock } from "proper-lockfile";
 
 import type { TwoSlashReturn } from "@typescript/twoslash";
 
-const SERVER_TABLE_FILE = "/tmp/twoslash-rust-servers.json";
-
 export const DEFAULT_SERVER_BINARY_IN_PATH = "rust-twoslash";
 
 export type UUID = string & { _brand: "uuid" };
-export type Address = string & { _brand: "address" };
 
 export type Server = {
   uuid: UUID;
 };
 
-type ServerTable = Record<UUID, Address>;
+/** What `rust-twoslash server list` knows about a running server. */
+type ServerRecord = {
+  uuid: UUID;
+  pid: number;
+  transport: "tcp" | "unix" | "http";
+  /** A TCP address, or the path of a Unix socket. */
+  address: string;
+  token?: string;
+  startedAt: number;
+};
 
-function readServerTable(): ServerTable {
-  const table = fs.readFileSync(SERVER_TABLE_FILE, "utf8");
-  return JSON.parse(table);
-}
+/** Per-snippet options sent to the server with each request. */
+export type RustSnippetOptions = {
+  edition?: "2015" | "2018" | "2021";
+  cut?: "markers" | "none";
+  customTags?: string[];
+  encoding?: "utf8" | "utf16";
+  clippy?: string[];
+  run?: boolean;
+  rustdoc?: string;
+};
 
-function commitServerTable(table: ServerTable) {
-  fs.writeFileSync(SERVER_TABLE_FILE, JSON.stringify(table));
-}
+const PROTOCOL_VERSION = 1;
 
-async function lockTable<T>(go: () => Promise<T>): Promise<T> {
-  if (!fs.existsSync(SERVER_TABLE_FILE)) {
-    // Ensure the table exists. NB: possible race here, but I don't think it's a
-    // big deal.
-    fs.writeFileSync(SERVER_TABLE_FILE, JSON.stringify({}));
+type Response<T> =
+  | { version: number; id?: string; result: T }
+  | {
+      version: number;
+      id?: string;
+      /** `partial` holds whatever results were ready when a request timed out or was cancelled. */
+      error: { kind: string; message: string; partial?: T };
+    };
+
+function unwrapResponse<T>(response: Response<T>): T {
+  if ("error" in response) {
+    throw new Error(`twoslash-rust ${response.error.kind}: ${response.error.message}`);
   }
-  const release = await lock(SERVER_TABLE_FILE);
-  const result = await go();
-  await release();
-  return result;
+  return response.result;
 }
 
-async function addServer(uuid: UUID, address: Address) {
-  return lockTable(async () => {
-    const table = readServerTable();
-    table[uuid] = address;
-    commitServerTable(table);
-  });
-}
+const execFile = promisify(cp.execFile);
 
-async function removeServer(uuid: UUID) {
-  return lockTable(async () => {
-    const table = readServerTable();
-    delete table[uuid];
-    commitServerTable(table);
-  });
+/** Runs `rust-twoslash server <args>`, and returns what it prints. */
+async function serverCommand(
+  serverBinaryPath: string,
+  args: string[],
+  env: NodeJS.ProcessEnv = process.env
+): Promise<string> {
+  const { stdout } = await execFile(serverBinaryPath, ["server", ...args], { env });
+  return stdout;
 }
 
-async function getServer(uuid: UUID): Promise<[string, number]> {
-  return lockTable(async () => {
-    const table = readServerTable();
-    const addr = table[uuid];
-    const [host, port] = addr.split(":");
-    return [host, Number(port)];
-  });
+/** Where to connect to a server: a Unix socket path, or a TCP host and port. */
+type ConnectOptions = net.IpcNetConnectOpts | net.TcpNetConnectOpts;
+
+async function getServer(
+  uuid: UUID,
+  serverBinaryPath: string
+): Promise<[ConnectOptions, string | undefined]> {
+  const records: ServerRecord[] = JSON.parse(await serverCommand(serverBinaryPath, ["list"]));
+  const record = records.find((record) => record.uuid === uuid);
+  if (!record) {
+    throw new Error(`twoslash-rust: no server ${uuid} is running`);
+  }
+  const { transport, address, token } = record;
+  switch (transport) {
+    case "unix":
+      return [{ path: address }, token];
+    case "tcp": {
+      const [host, port] = address.split(":");
+      return [{ host, port: Number(port) }, token];
+    }
+    default:
+      throw new Error(`twoslash-rust: server ${uuid} does not speak the framed protocol`);
+  }
 }
 
-export async function startServer(useCargo: boolean = false, projectName?: string, serverBinaryPath: string = DEFAULT_SERVER_BINARY_IN_PATH): Promise<Server> {
-  const uuid = uuidv4() as UUID;
+/** When a server should shut itself down, besides when it is asked to. */
+export type ServerLifetime = {
+  /** Shut down after this long without requests. */
+  idleTimeoutMs?: number;
+  /** Shut down when this process exits, even if it never calls `shutdownServer`. */
+  watchParent?: boolean;
+};
 
-  const env: Record<string, string> = {
+/**
+ * Starts a server in the background. With the "unix" transport, the server
+ * listens on a Unix socket that only the current user may connect to, rather
+ * than on a local TCP port.
+ */
+export async function startServer(
+  useCargo: boolean = false,
+  projectName?: string,
+  serverBinaryPath: string = DEFAULT_SERVER_BINARY_IN_PATH,
+  transport: "tcp" | "unix" = "tcp",
+  lifetime: ServerLifetime = {}
+): Promise<Server> {
+  const env: NodeJS.ProcessEnv = {
     ...process.env,
-    TWOSLASH_SERVER_UUID: uuid,
     TWOSLASH_USE_CARGO: useCargo ? "1" : "0",
+    TWOSLASH_TRANSPORT: transport,
   };
   if (projectName) {
     env.TWOSLASH_PROJECT_NAME = projectName;
   }
+  if (lifetime.idleTimeoutMs) {
+    env.TWOSLASH_IDLE_TIMEOUT_MS = String(lifetime.idleTimeoutMs);
+  }
+  if (lifetime.watchParent) {
+    env.TWOSLASH_PARENT_PID = String(process.pid);
+  }
 
-  const child = cp.spawn(serverBinaryPath, [], {
-    env,
-    detached: true,
-    stdio: ["ignore", "pipe", "ignore"],
-  });
-
-  const server_address = await new Promise<Address>((resolve, reject) => {
-    let stdout = "";
-    child.stdout.on("data", (e) => {
-      stdout += e.toString("utf8");
-      if (stdout.includes("\n")) {
-        child.stdout.destroy();
-        resolve(stdout.trim() as Address);
-      }
-    });
-    child.on("exit", () => reject());
-  });
-
-  await addServer(uuid, server_address);
-
-  child.unref();
-
+  // `server start` returns once the server is ready, with its UUID.
+  const uuid = (await serverCommand(serverBinaryPath, ["start"], env)).trim() as UUID;
   return { uuid };
 }
 
+/**
+ * Frames start with these bytes, then the framing version, then the length of
+ * the UTF-8 payload as a 32-bit big-endian integer.
+ */
+const FRAME_MAGIC = Buffer.from("TWS", "ascii");
+const FRAME_VERSION = 1;
+const FRAME_HEADER_SIZE = FRAME_MAGIC.length + 1 + 4;
+/** The largest payload the server reads or writes. */
+const MAX_FRAME = 16 * 1024 * 1024;
+
 function protocolWrite(stream: Writable, data: string) {
-  const buffer = Buffer.from(data, "binary");
-  const protoBuffer = Buffer.alloc(4 + buffer.length);
-  protoBuffer.writeUInt32BE(buffer.length);
-  buffer.copy(protoBuffer, 4);
-  stream.write(protoBuffer);
+  const payload = Buffer.from(data, "utf8");
+  if (payload.length > MAX_FRAME) {
+    throw new Error(`twoslash-rust: request of ${payload.length} bytes is too large`);
+  }
+  const header = Buffer.alloc(FRAME_HEADER_SIZE);
+  FRAME_MAGIC.copy(header);
+  header.writeUInt8(FRAME_VERSION, FRAME_MAGIC.length);
+  header.writeUInt32BE(payload.length, FRAME_MAGIC.length + 1);
+  stream.write(Buffer.concat([header, payload]));
 }
 
-async function protocolRead(stream: Readable): Promise<string> {
-  return new Promise<string>((resolve) => {
-    let buffer = Buffer.from("");
-    let messageSize: number | undefined;
-    stream.on("data", (chunk) => {
-      buffer = Buffer.concat([buffer, chunk], buffer.length + chunk.length);
-      if (buffer.length >= 4) {
-        messageSize = buffer.readUInt32BE();
+/**
+ * A connection to a server that stays open across requests. Requests may be
+ * pipelined; responses are matched up to requests by id. The server answers a
+ * connection's requests one at a time, so requests are cancelled over a
+ * connection of their own.
+ */
+class Connection {
+  private buffer = Buffer.alloc(0);
+  private pending = new Map<string, (response: Response<unknown>) => void>();
+  closed = false;
+
+  constructor(
+    private socket: net.Socket,
+    private address: ConnectOptions,
+    private token?: string
+  ) {
+    socket.on("data", (chunk) => this.onData(chunk));
+    // Without a listener, errors like ECONNRESET would be thrown from the event loop.
+    socket.on("error", (e) => {
+      this.closed = true;
+      this.failPending({ kind: "connectionError", message: e.message });
+    });
+    socket.on("close", () => {
+      this.closed = true;
+      this.failPending({ kind: "connectionClosed", message: "the server hung up" });
+    });
+    // Don't keep the process alive just for an idle connection.
+    socket.unref();
+  }
+
+  static async open(address: ConnectOptions, token?: string): Promise<Connection> {
+    return new Promise((resolve, reject) => {
+      const socket = new net.Socket();
+      socket.once("error", reject);
+      socket.connect(address, () => {
+        socket.off("error", reject);
+        resolve(new Connection(socket, address, token));
+      });
+    });
+  }
+
+  /**
+   * Sends a request and waits for its response. If `signal` aborts first, the
+   * server is asked to cancel the request, which then gets a `cancelled` error.
+   */
+  request<T>(body: Record<string, unknown>, signal?: AbortSignal): Promise<Response<T>> {
+    const id = uuidv4();
+    const envelope = { version: PROTOCOL_VERSION, id, token: this.token, ...body };
+    protocolWrite(this.socket, JSON.stringify(envelope));
+    const response = new Promise<Response<T>>((resolve) => {
+      this.pending.set(id, resolve as (response: Response<unknown>) => void);
+    });
+    if (signal?.aborted) {
+      this.cancel(id);
+    } else {
+      signal?.addEventListener("abort", () => this.cancel(id), { once: true });
+    }
+    return response;
+  }
+
+  /** Asks the server to cancel request `id`, if it is still waiting for a response. */
+  private async cancel(id: string) {
+    if (!this.pending.has(id)) {
+      return;
+    }
+    try {
+      const connection = await Connection.open(this.address, this.token);
+      try {
+        await connection.request<boolean>({ kind: "cancel", request: id });
+      } finally {
+        connection.close();
       }
-      if (messageSize && buffer.length >= messageSize + 4) {
-        let result = buffer.toString("utf8", 4, 4 + messageSize);
-        resolve(result);
+    } catch {
+      // The request still finishes, or times out, on its own.
+    }
+  }
+
+  close() {
+    this.socket.destroy();
+  }
+
+  /** Answers every request still waiting for a response with `error`. */
+  private failPending(error: { kind: string; message: string }) {
+    this.pending.forEach((resolve) => resolve({ version: PROTOCOL_VERSION, error }));
+    this.pending.clear();
+  }
+
+  private onData(chunk: Buffer) {
+    this.buffer = Buffer.concat([this.buffer, chunk]);
+    while (this.buffer.length >= FRAME_HEADER_SIZE) {
+      const magic = this.buffer.subarray(0, FRAME_MAGIC.length);
+      const version = this.buffer.readUInt8(FRAME_MAGIC.length);
+      const messageSize = this.buffer.readUInt32BE(FRAME_MAGIC.length + 1);
+      if (!magic.equals(FRAME_MAGIC) || version !== FRAME_VERSION || messageSize > MAX_FRAME) {
+        // We can't tell where the next frame starts, so give up on the connection.
+        this.close();
+        return;
       }
-    });
-  });
+      if (this.buffer.length < FRAME_HEADER_SIZE + messageSize) {
+        break;
+      }
+      const frame = this.buffer.toString("utf8", FRAME_HEADER_SIZE, FRAME_HEADER_SIZE + messageSize);
+      this.buffer = this.buffer.subarray(FRAME_HEADER_SIZE + messageSize);
+
+      let response: Response<unknown>;
+      try {
+        response = JSON.parse(frame);
+      } catch (e) {
+        // We can't tell which request this answers, so none of them will get their response.
+        this.failPending({ kind: "badResponse", message: `unreadable response: ${e}` });
+        continue;
+      }
+      if (!response.id) {
+        // The server couldn't tell which request this answers, like when a frame is too large, so
+        // it may answer any of them.
+        if ("error" in response) {
+          this.failPending(response.error);
+        }
+        continue;
+      }
+      const resolve = this.pending.get(response.id);
+      if (resolve) {
+        this.pending.delete(response.id);
+        resolve(response);
+      }
+    }
+  }
 }
 
-export async function runWithServer(code: string, serverId: UUID): Promise<TwoSlashReturn> {
-  const [host, port] = await getServer(serverId);
-  const client = new net.Socket();
-  client.connect(port, host, () => {
-    protocolWrite(client, code);
-  });
-
-  return protocolRead(client).then((data) => {
-    client.destroy();
-    return JSON.parse(data);
-  });
+const connections = new Map<UUID, Connection>();
+
+async function getConnection(serverId: UUID, serverBinaryPath: string): Promise<Connection> {
+  const existing = connections.get(serverId);
+  if (existing && !existing.closed) {
+    return existing;
+  }
+  const connection = await Connection.open(...(await getServer(serverId, serverBinaryPath)));
+  connections.set(serverId, connection);
+  return connection;
 }
 
-export async function shutdownServer(serverId: UUID) {
-  const [host, port] = await getServer(serverId);
-  const client = new net.Socket();
+/** Analyzes a snippet on a server. Aborting `signal` cancels the analysis. */
+export async function runWithServer(
+  code: string,
+  serverId: UUID,
+  options: RustSnippetOptions = {},
+  profile?: string,
+  timeoutMs?: number,
+  serverBinaryPath: string = DEFAULT_SERVER_BINARY_IN_PATH,
+  signal?: AbortSignal
+): Promise<TwoSlashReturn> {
+  const connection = await getConnection(serverId, serverBinaryPath);
+  const response = await connection.request<TwoSlashReturn>(
+    {
+      kind: "twoslash",
+      code,
+      options,
+      profile,
+      timeoutMs,
+    },
+    signal
+  );
+  return unwrapResponse(response);
+}
 
-  return new Promise((resolve) => {
-    client.connect(port, host, () => {
-      protocolWrite(client, `Shutdown ${serverId}`);
-      client.destroy();
-      removeServer(serverId).then(resolve);
-    });
-  });
+/** One snippet of a batch; anything left out comes from the batch. */
+export type RustSnippet = {
+  code: string;
+  options?: RustSnippetOptions;
+  profile?: string;
+  timeoutMs?: number;
+};
+
+/** What became of one snippet of a batch. */
+export type BatchOutcome =
+  | { result: TwoSlashReturn }
+  | { error: { kind: string; message: string; partial?: TwoSlashReturn } };
+
+/**
+ * Analyzes several snippets in one request. The server analyzes them as
+ * concurrently as it can, and answers with each one's outcome, in order.
+ * Aborting `signal` cancels every snippet that hasn't finished.
+ */
+export async function runBatchWithServer(
+  snippets: RustSnippet[],
+  serverId: UUID,
+  profile?: string,
+  timeoutMs?: number,
+  serverBinaryPath: string = DEFAULT_SERVER_BINARY_IN_PATH,
+  signal?: AbortSignal
+): Promise<BatchOutcome[]> {
+  const connection = await getConnection(serverId, serverBinaryPath);
+  const response = await connection.request<BatchOutcome[]>(
+    {
+      kind: "batch",
+      // Offsets are used to index into JavaScript strings.
+      snippets: snippets.map((snippet) => ({
+        ...snippet,
+        options: { encoding: "utf16", ...snippet.options },
+      })),
+      profile,
+      timeoutMs,
+    },
+    signal
+  );
+  return unwrapResponse(response);
+}
+
+/**
+ * A change to a session's document: the text between the UTF-16 offsets
+ * `start` and `end` is replaced with `text`. Each edit's offsets are into the
+ * document as the edits before it left it.
+ */
+export type RustEdit = { range: { start: number; end: number }; text: string };
+
+/**
+ * Opens a document on the server, which later edits apply to. Each session
+ * has a project of its own, so that rust-analyzer can reuse what it computed
+ * for the document before an edit; the document is still analyzed again in
+ * full. Returns the twoslash result for `code`.
+ */
+export async function openSession(
+  session: string,
+  code: string,
+  serverId: UUID,
+  options: RustSnippetOptions = {},
+  profile?: string,
+  timeoutMs?: number,
+  serverBinaryPath: string = DEFAULT_SERVER_BINARY_IN_PATH,
+  signal?: AbortSignal
+): Promise<TwoSlashReturn> {
+  const connection = await getConnection(serverId, serverBinaryPath);
+  const response = await connection.request<TwoSlashReturn>(
+    {
+      kind: "openSession",
+      session,
+      code,
+      // Offsets, including those of edits, index into JavaScript strings.
+      options: { encoding: "utf16", ...options },
+      profile,
+      timeoutMs,
+    },
+    signal
+  );
+  return unwrapResponse(response);
+}
+
+/** Applies edits to a session's document, and returns its new twoslash result. */
+export async function editSession(
+  session: string,
+  edits: RustEdit[],
+  serverId: UUID,
+  timeoutMs?: number,
+  serverBinaryPath: string = DEFAULT_SERVER_BINARY_IN_PATH,
+  signal?: AbortSignal
+): Promise<TwoSlashReturn> {
+  const connection = await getConnection(serverId, serverBinaryPath);
+  const response = await connection.request<TwoSlashReturn>(
+    {
+      kind: "editSession",
+      session,
+      edits,
+      timeoutMs,
+    },
+    signal
+  );
+  return unwrapResponse(response);
+}
+
+/** Closes a session. Returns whether it was open. */
+export async function closeSession(
+  session: string,
+  serverId: UUID,
+  serverBinaryPath: string = DEFAULT_SERVER_BINARY_IN_PATH
+): Promise<boolean> {
+  const connection = await getConnection(serverId, serverBinaryPath);
+  return unwrapResponse(await connection.request<boolean>({ kind: "closeSession", session }));
+}
+
+export async function shutdownServer(
+  serverId: UUID,
+  serverBinaryPath: string = DEFAULT_SERVER_BINARY_IN_PATH
+) {
+  connections.get(serverId)?.close();
+  connections.delete(serverId);
+
+  await serverCommand(serverBinaryPath, ["stop", serverId]);
 }
 
 export function runStandalone(code: string, serverBinaryPath: string): TwoSlashReturn {
@@ -0,0 +1,67 @@
+//! The shared secret clients must present with every request to a server.
+
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+use anyhow::Result;
+
+/// How many random bytes go into a token.
+const TOKEN_BYTES: usize = 32;
+
+/// Fills `bytes` with random bytes from the operating system.
+pub fn random_bytes(bytes: &mut [u8]) -> Result<()> {
+    File::open("/dev/urandom")?.read_exact(bytes)?;
+    Ok(())
+}
+
+/// Generates a new random token, as hex.
+pub fn generate_token() -> Result<String> {
+    let mut bytes = [0; TOKEN_BYTES];
+    random_bytes(&mut bytes)?;
+    Ok(bytes.iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+/// Writes `token` to a new file at `path` that only the current user can read.
+pub fn write_token_file(path: &Path, token: &str) -> Result<()> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?;
+    writeln!(file, "{}", token)?;
+    Ok(())
+}
+
+/// Compares tokens in time that does not depend on where they differ, so that a client can't
+/// guess the token byte by byte.
+pub fn tokens_match(expected: &str, given: &str) -> bool {
+    let (expected, given) = (expected.as_bytes(), given.as_bytes());
+    expected.len() == given.len()
+        && expected
+            .iter()
+            .zip(given)
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+#[cfg(test)]
+mod test {
+    use super::{generate_token, tokens_match, TOKEN_BYTES};
+
+    #[test]
+    fn test_generated_tokens_differ() {
+        let token = generate_token().unwrap();
+        assert_eq!(token.len(), TOKEN_BYTES * 2);
+        assert_ne!(token, generate_token().unwrap());
+    }
+
+    #[test]
+    fn test_matching() {
+        assert!(tokens_match("abc", "abc"));
+        assert!(!tokens_match("abc", "abd"));
+        assert!(!tokens_match("abc", "ab"));
+        assert!(!tokens_match("abc", ""));
+    }
+}
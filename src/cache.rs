@@ -0,0 +1,287 @@
+//! Caches twoslash results by everything that goes into them, so that unchanged snippets needn't
+//! be analyzed again.
+
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::Mutex;
+use std::time::UNIX_EPOCH;
+
+use anyhow::Result;
+use lazy_static::lazy_static;
+use serde_json::json;
+
+use crate::options::SnippetOptions;
+use crate::project::ProjectSettings;
+use crate::twoslash::TwoSlash;
+
+/// The version of the Rust toolchain snippets are analyzed (and built) with, which results depend
+/// on through the sysroot.
+pub fn toolchain_version() -> String {
+    Command::new("rustc")
+        .arg("--version")
+        .output()
+        .ok()
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map_or_else(
+            || "unknown".to_string(),
+            |version| version.trim().to_string(),
+        )
+}
+
+/// Identifies the build of this binary, which results depend on through the vendored
+/// rust-analyzer as much as through our own code. The crate's version doesn't change from one
+/// build to the next, but the binary's size and modification time do.
+fn build_id() -> String {
+    let metadata = std::env::current_exe().and_then(fs::metadata);
+    let modified = metadata
+        .as_ref()
+        .ok()
+        .and_then(|metadata| metadata.modified().ok())
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok());
+    match (metadata, modified) {
+        (Ok(metadata), Some(modified)) => format!(
+            "{}-{}-{}",
+            env!("CARGO_PKG_VERSION"),
+            metadata.len(),
+            modified.as_nanos()
+        ),
+        _ => format!("{}-unknown", env!("CARGO_PKG_VERSION")),
+    }
+}
+
+lazy_static! {
+    static ref BUILD_ID: String = build_id();
+}
+
+/// The key a result is cached under: everything that determines it, other than where the project
+/// lives on disk.
+pub fn key(
+    code: &str,
+    options: &SnippetOptions,
+    settings: &ProjectSettings,
+    toolchain: &str,
+) -> String {
+    json!({
+        "twoslash": *BUILD_ID,
+        "toolchain": toolchain,
+        "kind": settings.kind,
+        "projectName": settings.project_name,
+        "clippy": settings.use_clippy,
+        "procMacros": settings.with_proc_macros,
+        "dependencies": settings.dependencies,
+        "options": options,
+        "code": code,
+    })
+    .to_string()
+}
+
+/// A short, file-name-safe digest of a key, or of code. This is 64-bit FNV-1a, rather than std's
+/// hasher, whose output may change between Rust versions; digests name files on disk, so they
+/// must stay the same from one build to the next.
+pub fn digest(key: &str) -> String {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0000_0100_0000_01b3;
+    let hash = key.bytes().fold(OFFSET_BASIS, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(PRIME)
+    });
+    format!("{:016x}", hash)
+}
+
+/// Whether a result may be cached. Snippets that were run (or at least built) are left out, so
+/// that they are run again every time, since what they print may change from one run to the next.
+pub fn cacheable(result: &TwoSlash) -> bool {
+    match &result.execution {
+        Some(execution) => execution.reason.is_some(),
+        None => true,
+    }
+}
+
+#[derive(Default)]
+struct Entries {
+    results: HashMap<String, TwoSlash>,
+    /// Keys, from the least recently used entry to the most.
+    order: VecDeque<String>,
+}
+
+impl Entries {
+    /// Marks the entry for `key` as the most recently used.
+    fn touch(&mut self, key: &str) {
+        if let Some(index) = self.order.iter().position(|other| other == key) {
+            if let Some(key) = self.order.remove(index) {
+                self.order.push_back(key);
+            }
+        }
+    }
+}
+
+/// Results in memory, up to a number of entries. The least recently used are evicted first.
+pub struct MemoryCache {
+    capacity: usize,
+    entries: Mutex<Entries>,
+}
+
+impl MemoryCache {
+    pub fn new(capacity: usize) -> Self {
+        MemoryCache {
+            capacity,
+            entries: Mutex::new(Entries::default()),
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<TwoSlash> {
+        let mut entries = self.entries.lock().unwrap();
+        let result = entries.results.get(key).cloned()?;
+        entries.touch(key);
+        Some(result)
+    }
+
+    pub fn insert(&self, key: String, result: TwoSlash) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        match entries.results.insert(key.clone(), result) {
+            Some(_) => entries.touch(&key),
+            None => entries.order.push_back(key),
+        }
+        while entries.order.len() > self.capacity {
+            if let Some(oldest) = entries.order.pop_front() {
+                entries.results.remove(&oldest);
+            }
+        }
+    }
+}
+
+/// Results as JSON files in a directory, which persists between runs.
+pub struct DiskCache {
+    dir: PathBuf,
+}
+
+impl DiskCache {
+    pub fn new(dir: PathBuf) -> Result<Self> {
+        fs::create_dir_all(&dir)?;
+        Ok(DiskCache { dir })
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", digest(key)))
+    }
+
+    /// Returns the cached result for `key` as JSON, if there is one. Unreadable entries count as
+    /// misses.
+    pub fn get(&self, key: &str) -> Option<serde_json::Value> {
+        let contents = fs::read_to_string(self.path(key)).ok()?;
+        let mut entry: serde_json::Value = serde_json::from_str(&contents).ok()?;
+        // Digests can collide, so entries remember their whole key.
+        match entry["key"].as_str() == Some(key) {
+            true => Some(entry["result"].take()),
+            false => None,
+        }
+    }
+
+    pub fn insert(&self, key: &str, result: &TwoSlash) -> Result<()> {
+        let entry = json!({ "key": key, "result": result });
+        // Write the entry whole, so that concurrent builds never read half of one.
+        let path = self.path(key);
+        let partial = path.with_extension(format!("{}.tmp", std::process::id()));
+        fs::write(&partial, entry.to_string())?;
+        fs::rename(&partial, &path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use super::{digest, key, MemoryCache};
+    use crate::options::SnippetOptions;
+    use crate::project::{ProjectKind, ProjectSettings};
+    use crate::twoslash::TwoSlash;
+
+    fn settings() -> ProjectSettings {
+        ProjectSettings {
+            kind: ProjectKind::SingleFile,
+            project_name: "twoslash-rust-project".to_string(),
+            dir: PathBuf::from("/tmp/a"),
+            use_clippy: false,
+            with_proc_macros: false,
+            dependencies: String::new(),
+        }
+    }
+
+    #[test]
+    fn test_keys_ignore_project_directory() {
+        let options = SnippetOptions::default();
+        let elsewhere = ProjectSettings {
+            dir: PathBuf::from("/tmp/b"),
+            ..settings()
+        };
+        assert_eq!(
+            key("fn main() {}", &options, &settings(), "rustc 1.60.0"),
+            key("fn main() {}", &options, &elsewhere, "rustc 1.60.0")
+        );
+    }
+
+    #[test]
+    fn test_keys_depend_on_inputs() {
+        let options = SnippetOptions::default();
+        let base = key("fn main() {}", &options, &settings(), "rustc 1.60.0");
+        assert_ne!(
+            base,
+            key("fn main() { }", &options, &settings(), "rustc 1.60.0")
+        );
+        assert_ne!(
+            base,
+            key("fn main() {}", &options, &settings(), "rustc 1.61.0")
+        );
+        let run = SnippetOptions {
+            run: true,
+            ..SnippetOptions::default()
+        };
+        assert_ne!(base, key("fn main() {}", &run, &settings(), "rustc 1.60.0"));
+        let cargo = ProjectSettings {
+            kind: ProjectKind::Cargo,
+            ..settings()
+        };
+        assert_ne!(base, key("fn main() {}", &options, &cargo, "rustc 1.60.0"));
+    }
+
+    #[test]
+    fn test_digests_are_stable() {
+        assert_eq!(digest(""), "cbf29ce484222325");
+        assert_eq!(digest("a"), "af63dc4c8601ec8c");
+        assert_eq!(digest("foobar"), "85944171f73967e8");
+    }
+
+    fn result(code: &str) -> TwoSlash {
+        TwoSlash {
+            code: code.to_string(),
+            extension: ".rs".to_string(),
+            highlights: vec![],
+            static_quick_infos: vec![],
+            queries: vec![],
+            tags: vec![],
+            errors: vec![],
+            playground_url: String::new(),
+            clippy: None,
+            execution: None,
+            expectation: None,
+        }
+    }
+
+    #[test]
+    fn test_evicts_the_least_recently_used() {
+        let cache = MemoryCache::new(2);
+        cache.insert("a".to_string(), result("a"));
+        cache.insert("b".to_string(), result("b"));
+        assert!(cache.get("a").is_some());
+        cache.insert("c".to_string(), result("c"));
+
+        assert!(cache.get("a").is_some());
+        assert!(cache.get("b").is_none());
+        assert!(cache.get("c").is_some());
+    }
+}
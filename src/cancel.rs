@@ -0,0 +1,119 @@
+//! Stopping analyses that take too long, or that a client no longer wants.
+
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use ra_ide::AnalysisHost;
+
+use crate::twoslash::TwoSlash;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Reason {
+    /// The request's deadline passed.
+    Timeout,
+    /// The client asked for the request to be cancelled.
+    Requested,
+}
+
+#[derive(Default)]
+struct State {
+    reason: Option<Reason>,
+    /// The host of the project analyzing the request, while it is being analyzed.
+    host: Option<Arc<Mutex<AnalysisHost>>>,
+}
+
+impl State {
+    fn attached_to(&self, host: &Arc<Mutex<AnalysisHost>>) -> bool {
+        self.host
+            .as_ref()
+            .is_some_and(|attached| Arc::ptr_eq(attached, host))
+    }
+}
+
+/// A handle for cancelling one request, shared between whoever wants it cancelled and the worker
+/// analyzing it.
+#[derive(Clone, Default)]
+pub struct Cancel(Arc<Mutex<State>>);
+
+impl Cancel {
+    /// Cancels the request, if it has not been cancelled already. If it is being analyzed,
+    /// rust-analyzer is asked to stop, which makes pending and future queries on the analysis
+    /// fail with `Cancelled`.
+    pub fn cancel(&self, reason: Reason) {
+        let host = {
+            let mut state = self.0.lock().unwrap();
+            if state.reason.is_some() {
+                return;
+            }
+            state.reason = Some(reason);
+            state.host.clone()
+        };
+        if let Some(host) = host {
+            // Cancelling applies a change to the database, which waits for the analysis to notice
+            // and let go of it. Don't make the canceller wait too.
+            let state = Arc::clone(&self.0);
+            thread::spawn(move || {
+                // A poisoned host belongs to a project that is being thrown away anyway.
+                if let Ok(mut host_guard) = host.lock() {
+                    // By the time we get the host, the request may have finished, and the worker
+                    // moved on to another one, which must not be cancelled in its place. Workers
+                    // need the host to start on a request, so holding it keeps them from doing
+                    // so while we check.
+                    if state.lock().unwrap().attached_to(&host) {
+                        host_guard.request_cancellation();
+                    }
+                }
+            });
+        }
+    }
+
+    pub fn reason(&self) -> Option<Reason> {
+        self.0.lock().unwrap().reason
+    }
+
+    /// Marks the request as being analyzed with `host`. Returns `false` if the request was
+    /// cancelled before it got the chance.
+    pub fn attach(&self, host: &Arc<Mutex<AnalysisHost>>) -> bool {
+        let mut state = self.0.lock().unwrap();
+        state.host = Some(Arc::clone(host));
+        state.reason.is_none()
+    }
+
+    /// Marks the request as done, so cancelling it no longer affects the host.
+    pub fn detach(&self) {
+        self.0.lock().unwrap().host = None;
+    }
+
+    pub fn same(&self, other: &Cancel) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+/// An analysis that was stopped before it finished, with whatever results were ready by then.
+pub struct Interrupted {
+    /// Why the analysis was stopped, or `None` if it was not through a `Cancel`.
+    pub reason: Option<Reason>,
+    pub partial: Option<TwoSlash>,
+}
+
+impl fmt::Debug for Interrupted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Interrupted")
+            .field("reason", &self.reason)
+            .field("partial", &self.partial.is_some())
+            .finish()
+    }
+}
+
+impl fmt::Display for Interrupted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.reason {
+            Some(Reason::Timeout) => write!(f, "analysis timed out"),
+            Some(Reason::Requested) => write!(f, "analysis was cancelled"),
+            None => write!(f, "analysis was interrupted"),
+        }
+    }
+}
+
+impl std::error::Error for Interrupted {}
@@ -0,0 +1,199 @@
+//! Runs cargo subcommands against a scaffolded project and reads back the compiler messages
+//! they emit with `--message-format=json`.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Stdio};
+
+use anyhow::Result;
+use serde::Deserialize;
+
+use crate::cancel::Cancel;
+use crate::runner::{self, Killed};
+
+#[derive(Deserialize)]
+struct CargoMessage {
+    reason: String,
+    message: Option<CompilerMessage>,
+    /// Present on `compiler-artifact` messages for binaries.
+    executable: Option<PathBuf>,
+}
+
+#[derive(Deserialize)]
+pub struct DiagnosticCode {
+    pub code: String,
+}
+
+#[derive(Deserialize)]
+pub struct DiagnosticSpan {
+    pub file_name: String,
+    pub byte_start: u32,
+    pub byte_end: u32,
+    pub is_primary: bool,
+}
+
+/// A rustc (or clippy) diagnostic, as emitted by cargo.
+#[derive(Deserialize)]
+pub struct CompilerMessage {
+    pub message: String,
+    pub code: Option<DiagnosticCode>,
+    pub level: String,
+    pub spans: Vec<DiagnosticSpan>,
+    pub children: Vec<CompilerMessage>,
+}
+
+impl CompilerMessage {
+    /// The primary span of this message in `file_name`, if there is one.
+    pub fn primary_span_in(&self, file_name: &str) -> Option<&DiagnosticSpan> {
+        self.spans
+            .iter()
+            .find(|span| span.is_primary && span.file_name == file_name)
+    }
+
+    /// The message along with any `help` and `note` children, like rustc renders them.
+    pub fn render(&self) -> String {
+        let mut rendered = self.message.clone();
+        for child in self.children.iter() {
+            if child.level == "help" || child.level == "note" {
+                rendered.push_str(&format!("\n{}: {}", child.level, child.message));
+            }
+        }
+        rendered
+    }
+}
+
+/// The codes of the errors in `messages`, or their messages for errors without a code. This
+/// includes errors outside of any snippet's visible part, and those without a span (like
+/// E0601), but not rustc's closing "aborting due to previous error", which has neither.
+pub fn error_codes(messages: &[CompilerMessage]) -> Vec<String> {
+    messages
+        .iter()
+        .filter(|msg| msg.level == "error" && (msg.code.is_some() || !msg.spans.is_empty()))
+        .map(|msg| match &msg.code {
+            Some(code) => code.code.clone(),
+            None => msg.message.clone(),
+        })
+        .collect()
+}
+
+/// Path of the binary target's source file, relative to the project root.
+pub const MAIN_RS: &str = "src/main.rs";
+
+fn cargo() -> Command {
+    Command::new(std::env::var("CARGO").unwrap_or_else(|_| "cargo".to_string()))
+}
+
+/// How a cargo command exited, and what it printed.
+struct Output {
+    status: ExitStatus,
+    stdout: String,
+    /// Only the start of it; cargo's messages go to stdout, so this is mostly progress and
+    /// cargo's own errors.
+    stderr: String,
+}
+
+/// Runs a cargo command to completion. If the request it is for is cancelled (or times out)
+/// first, cargo is killed, and the result is an `Interrupted` error.
+fn run(mut cmd: Command, cancel: &Cancel) -> Result<Output> {
+    let mut child = cmd
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let stdout = runner::capture(child.stdout.take(), usize::MAX);
+    let stderr = runner::capture(child.stderr.take(), runner::DEFAULT_MAX_OUTPUT);
+    // Cargo has no deadline of its own; the request's timeout cancels it.
+    let status = match runner::wait_or_kill(&mut child, None, cancel)? {
+        (_, Some(Killed::Cancelled(reason))) => return Err(runner::interrupted(reason)),
+        (status, _) => status,
+    };
+    let (stdout, _) = stdout.join().unwrap_or_default();
+    let (stderr, _) = stderr.join().unwrap_or_default();
+    Ok(Output {
+        status,
+        stdout,
+        stderr,
+    })
+}
+
+fn cargo_messages(stdout: &str) -> Vec<CargoMessage> {
+    stdout
+        .lines()
+        .filter_map(|line| serde_json::from_str::<CargoMessage>(line).ok())
+        .collect()
+}
+
+fn compiler_messages(messages: Vec<CargoMessage>) -> Vec<CompilerMessage> {
+    messages
+        .into_iter()
+        .filter(|msg| msg.reason == "compiler-message")
+        .filter_map(|msg| msg.message)
+        .collect()
+}
+
+/// Runs `cargo clippy` in the project at `root`, enabling each of `lints` (a lint group like
+/// `pedantic`, or a single lint name) as a warning. Only clippy's own lints are returned, since
+/// rustc's diagnostics duplicate rust-analyzer's. Fails if clippy itself does, like when it is
+/// not installed.
+pub fn clippy(root: &Path, lints: &[String], cancel: &Cancel) -> Result<Vec<CompilerMessage>> {
+    let mut cmd = cargo();
+    cmd.current_dir(root)
+        .args(["clippy", "--quiet", "--message-format=json", "--"]);
+    for lint in lints {
+        cmd.arg("-W").arg(format!("clippy::{}", lint));
+    }
+    let output = run(cmd, cancel)?;
+    let messages = compiler_messages(cargo_messages(&output.stdout));
+    // Clippy also fails when the snippet doesn't compile, which rustc will have said.
+    if !output.status.success() && !messages.iter().any(|msg| msg.level == "error") {
+        anyhow::bail!("cargo clippy failed: {}", output.stderr.trim());
+    }
+    let lints = messages
+        .into_iter()
+        .filter(|msg| {
+            msg.code
+                .as_ref()
+                .is_some_and(|code| code.code.starts_with("clippy::"))
+        })
+        .collect();
+    Ok(lints)
+}
+
+/// Runs `cargo check` in the project at `root`.
+pub fn check(root: &Path, cancel: &Cancel) -> Result<Vec<CompilerMessage>> {
+    let mut cmd = cargo();
+    cmd.current_dir(root)
+        .args(["check", "--quiet", "--message-format=json"]);
+    let output = run(cmd, cancel)?;
+    Ok(compiler_messages(cargo_messages(&output.stdout)))
+}
+
+/// The result of building a project's binary.
+pub struct Build {
+    /// Path to the built binary, or `None` if the build failed.
+    pub executable: Option<PathBuf>,
+    pub messages: Vec<CompilerMessage>,
+}
+
+/// Builds `source` as the binary target of the project at `root`.
+pub fn build_bin(root: &Path, source: &str, cancel: &Cancel) -> Result<Build> {
+    // The binary target only exists for the duration of the build, so that it is not picked up
+    // by other cargo commands (like clippy) run against the project.
+    let main_rs = root.join(MAIN_RS);
+    fs::write(&main_rs, source)?;
+    let mut cmd = cargo();
+    cmd.current_dir(root)
+        .args(["build", "--bins", "--quiet", "--message-format=json"]);
+    let output = run(cmd, cancel);
+    fs::remove_file(&main_rs)?;
+
+    let messages = cargo_messages(&output?.stdout);
+    let executable = match messages.iter().find_map(|msg| msg.executable.clone()) {
+        Some(executable) if executable.exists() => Some(executable),
+        _ => None,
+    };
+    Ok(Build {
+        executable,
+        messages: compiler_messages(messages),
+    })
+}
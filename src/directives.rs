@@ -0,0 +1,134 @@
+use lazy_static::lazy_static;
+use regex::Regex;
+
+use crate::options::SnippetOptions;
+use crate::rustdoc::CodeBlockAttributes;
+use crate::twoslash::Tag;
+
+lazy_static! {
+    static ref DIRECTIVE: Regex =
+        Regex::new(r#"^\s*//\s*@(?P<name>\w+)(\s*:\s*(?P<annotation>.*))?$"#).unwrap();
+}
+
+/// Per-snippet settings given by `// @name: annotation` comments in the source.
+pub struct Directives {
+    /// Lint groups (or individual lints) to enable, from `// @clippy: pedantic, nursery`. `None`
+    /// if the snippet did not ask for clippy.
+    pub clippy: Option<Vec<String>>,
+    /// Build and execute the snippet, from `// @run`.
+    pub run: bool,
+    /// Rustdoc code block attributes to check the snippet against, from
+    /// `// @rustdoc: compile_fail, E0308`.
+    pub rustdoc: Option<CodeBlockAttributes>,
+    /// Comments named by the `customTags` option.
+    pub tags: Vec<Tag>,
+}
+
+fn split_list(annotation: Option<&str>) -> Vec<String> {
+    annotation
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Removes the directives we know about (and custom tags) from the source, returning the rest of
+/// the source and the parsed directives. Unknown `// @...` comments are left in place.
+/// Directives start out as given by `options`, and are overridden by the source.
+pub fn find_directives(src: &str, options: &SnippetOptions) -> (String, Directives) {
+    let mut directives = Directives {
+        clippy: options.clippy.clone(),
+        run: options.run,
+        rustdoc: options.rustdoc.as_deref().map(CodeBlockAttributes::parse),
+        tags: vec![],
+    };
+    let mut lines = vec![];
+    for line in src.lines() {
+        let capture = match DIRECTIVE.captures(line) {
+            Some(capture) => capture,
+            None => {
+                lines.push(line);
+                continue;
+            }
+        };
+        let annotation = capture.name("annotation").map(|a| a.as_str().trim());
+        match &capture["name"] {
+            "clippy" => directives.clippy = Some(split_list(annotation)),
+            "run" => directives.run = true,
+            "rustdoc" => {
+                directives.rustdoc =
+                    Some(CodeBlockAttributes::parse(annotation.unwrap_or_default()))
+            }
+            name if options.custom_tags.iter().any(|tag| tag == name) => {
+                directives.tags.push(Tag {
+                    name: name.to_string(),
+                    line: lines.len() as u32,
+                    annotation: annotation.map(str::to_string),
+                })
+            }
+            _ => lines.push(line),
+        }
+    }
+
+    (lines.join("\n"), directives)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::options::SnippetOptions;
+
+    use super::find_directives;
+
+    #[test]
+    fn test_find_directives() {
+        let src = r#"
+// @clippy: pedantic, nursery
+// @unknown: stays
+// @run
+fn foo() {}
+"#
+        .trim();
+        let (src, directives) = find_directives(src, &SnippetOptions::default());
+
+        assert_eq!(src, "// @unknown: stays\nfn foo() {}");
+        assert_eq!(
+            directives.clippy,
+            Some(vec!["pedantic".to_string(), "nursery".to_string()])
+        );
+        assert!(directives.run);
+    }
+
+    #[test]
+    fn test_bare_clippy_directive() {
+        let (src, directives) =
+            find_directives("// @clippy\nfn foo() {}", &SnippetOptions::default());
+
+        assert_eq!(src, "fn foo() {}");
+        assert_eq!(directives.clippy, Some(vec![]));
+    }
+
+    #[test]
+    fn test_custom_tags() {
+        let options = SnippetOptions {
+            custom_tags: vec!["annotate".to_string()],
+            run: true,
+            ..Default::default()
+        };
+        let src = r#"
+fn foo() {}
+// @annotate: left
+fn bar() {}
+"#
+        .trim();
+        let (src, directives) = find_directives(src, &options);
+
+        assert_eq!(src, "fn foo() {}\nfn bar() {}");
+        assert_eq!(directives.tags.len(), 1);
+        assert_eq!(directives.tags[0].name, "annotate");
+        assert_eq!(directives.tags[0].line, 1);
+        assert_eq!(directives.tags[0].annotation.as_deref(), Some("left"));
+        assert!(directives.run);
+    }
+}
@@ -0,0 +1,519 @@
+//! A small HTTP/1.1 front end to a `Server`, for clients that would rather not speak the framed
+//! protocol:
+//!
+//! - `POST /twoslash` takes `{ "code": ..., "options": ..., "profile": ..., "timeoutMs": ... }`
+//!   (all but `code` optional) and answers with the twoslash result.
+//! - `POST /batch` takes `{ "snippets": [...], "profile": ..., "timeoutMs": ... }`, where each
+//!   snippet is like the body of `POST /twoslash`, and answers with an array of `{ "result": ... }`
+//!   or `{ "error": ... }`, in order.
+//! - `POST /sessions/open` takes `{ "session": ..., "code": ..., "options": ..., "profile": ...,
+//!   "timeoutMs": ... }`, `POST /sessions/edit` takes `{ "session": ..., "edits": [...],
+//!   "timeoutMs": ... }`, and both answer with the twoslash result for the session's document.
+//!   `POST /sessions/close` takes `{ "session": ... }`. See `protocol::RequestBody`.
+//! - `GET /health` answers with `{ "status": "ok", "profiles": [...] }`.
+//! - `GET /status` answers with statistics about the server; see `Server::status`.
+//! - `POST /shutdown` takes `{ "uuid": ... }` and shuts the server down.
+//!
+//! Every request must carry the server's token as `Authorization: Bearer <token>`, if it has one.
+//!
+//! Failures are answered with an error status and `{ "error": { "kind": ..., "message": ... } }`,
+//! like the `error` of a framed response.
+
+use std::io::{BufRead, BufReader, Read, Write};
+
+use anyhow::Result;
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::options::SnippetOptions;
+use crate::protocol::{Edit, Snippet};
+use crate::server::{Failure, Server};
+
+/// The largest request body we accept.
+const MAX_BODY: usize = 8 * 1024 * 1024;
+/// The most header lines we accept in one request.
+const MAX_HEADERS: usize = 100;
+/// The longest request line or header line we accept, in bytes.
+const MAX_LINE: usize = 8 * 1024;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TwoslashRequest {
+    code: String,
+    #[serde(default)]
+    options: SnippetOptions,
+    #[serde(default)]
+    profile: Option<String>,
+    #[serde(default)]
+    timeout_ms: Option<u64>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BatchRequest {
+    snippets: Vec<Snippet>,
+    #[serde(default)]
+    profile: Option<String>,
+    #[serde(default)]
+    timeout_ms: Option<u64>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct OpenSessionRequest {
+    session: String,
+    code: String,
+    #[serde(default)]
+    options: SnippetOptions,
+    #[serde(default)]
+    profile: Option<String>,
+    #[serde(default)]
+    timeout_ms: Option<u64>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct EditSessionRequest {
+    session: String,
+    edits: Vec<Edit>,
+    #[serde(default)]
+    timeout_ms: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct CloseSessionRequest {
+    session: String,
+}
+
+#[derive(Deserialize)]
+struct ShutdownRequest {
+    uuid: String,
+}
+
+/// What a client sent next.
+enum Incoming {
+    Request(Request),
+    /// A request we couldn't make sense of, and the response to it.
+    Malformed(Response),
+    /// The client hung up between requests.
+    Closed,
+}
+
+struct Request {
+    method: String,
+    path: String,
+    body: Vec<u8>,
+    /// The bearer token from the `Authorization` header.
+    token: Option<String>,
+    /// Whether the client wants the connection closed after this request.
+    close: bool,
+}
+
+struct Response {
+    status: u16,
+    body: serde_json::Value,
+}
+
+impl Response {
+    fn ok(body: serde_json::Value) -> Self {
+        Response { status: 200, body }
+    }
+
+    fn error(status: u16, kind: &str, message: impl ToString) -> Self {
+        let body = json!({ "error": { "kind": kind, "message": message.to_string() } });
+        Response { status, body }
+    }
+
+    fn failure(failure: Failure) -> Self {
+        let status = match failure.kind {
+            "unknownProfile" | "unknownSession" => 404,
+            "badEdit" => 400,
+            "cancelled" | "sessionExists" => 409,
+            "analysisFailed" => 422,
+            "timeout" => 504,
+            _ => 500,
+        };
+        let mut response = Response::error(status, failure.kind, failure.message);
+        if let Some(partial) = failure.partial {
+            response.body["error"]["partial"] = json!(partial);
+        }
+        response
+    }
+}
+
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        411 => "Length Required",
+        413 => "Payload Too Large",
+        414 => "URI Too Long",
+        422 => "Unprocessable Entity",
+        431 => "Request Header Fields Too Large",
+        504 => "Gateway Timeout",
+        _ => "Internal Server Error",
+    }
+}
+
+/// Reads a line of at most `MAX_LINE` bytes, including its line ending. Returns `None` if the
+/// line is longer than that, and an empty string if the client hung up.
+fn read_line(reader: &mut impl BufRead) -> Result<Option<String>> {
+    let mut line = String::new();
+    reader.take(MAX_LINE as u64 + 1).read_line(&mut line)?;
+    if line.len() > MAX_LINE {
+        return Ok(None);
+    }
+    Ok(Some(line))
+}
+
+/// Reads the next request from a client. Only bodies with a `Content-Length` are supported.
+fn read_request(reader: &mut impl BufRead) -> Result<Incoming> {
+    let request_line = match read_line(reader)? {
+        Some(line) if line.is_empty() => return Ok(Incoming::Closed),
+        Some(line) => line,
+        None => {
+            let message = format!("request lines may be at most {} bytes", MAX_LINE);
+            let response = Response::error(414, "badRequest", message);
+            return Ok(Incoming::Malformed(response));
+        }
+    };
+    let mut parts = request_line.split_whitespace();
+    let (method, path, version) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(path), Some(version)) => (method, path, version),
+        _ => {
+            let response = Response::error(400, "badRequest", "malformed request line");
+            return Ok(Incoming::Malformed(response));
+        }
+    };
+    // HTTP/1.0 closes connections by default; 1.1 keeps them open.
+    let mut close = version == "HTTP/1.0";
+
+    let mut content_length = 0;
+    let mut chunked = false;
+    let mut token = None;
+    let mut headers = 0;
+    loop {
+        let line = match read_line(reader)? {
+            Some(line) if line.is_empty() => return Ok(Incoming::Closed),
+            Some(line) => line,
+            None => {
+                let message = format!("header lines may be at most {} bytes", MAX_LINE);
+                let response = Response::error(431, "badRequest", message);
+                return Ok(Incoming::Malformed(response));
+            }
+        };
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        headers += 1;
+        if headers > MAX_HEADERS {
+            let message = format!("requests may have at most {} headers", MAX_HEADERS);
+            let response = Response::error(431, "badRequest", message);
+            return Ok(Incoming::Malformed(response));
+        }
+        let (name, value) = match line.split_once(':') {
+            Some((name, value)) => (name.trim().to_ascii_lowercase(), value.trim()),
+            None => {
+                let response = Response::error(400, "badRequest", "malformed header");
+                return Ok(Incoming::Malformed(response));
+            }
+        };
+        match name.as_str() {
+            "content-length" => match value.parse() {
+                Ok(length) => content_length = length,
+                Err(_) => {
+                    let response = Response::error(400, "badRequest", "bad Content-Length");
+                    return Ok(Incoming::Malformed(response));
+                }
+            },
+            "authorization" => {
+                token = value
+                    .strip_prefix("Bearer ")
+                    .map(|token| token.trim().to_string())
+            }
+            "transfer-encoding" => chunked = !value.eq_ignore_ascii_case("identity"),
+            "connection" => match value.to_ascii_lowercase().as_str() {
+                "close" => close = true,
+                "keep-alive" => close = false,
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+
+    if chunked {
+        let message = "chunked bodies are not supported; send a Content-Length";
+        return Ok(Incoming::Malformed(Response::error(
+            411,
+            "badRequest",
+            message,
+        )));
+    }
+    if content_length > MAX_BODY {
+        let message = format!("bodies may be at most {} bytes", MAX_BODY);
+        return Ok(Incoming::Malformed(Response::error(
+            413,
+            "badRequest",
+            message,
+        )));
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)?;
+
+    Ok(Incoming::Request(Request {
+        method: method.to_string(),
+        path: path.to_string(),
+        body,
+        token,
+        close,
+    }))
+}
+
+fn write_response(writer: &mut impl Write, response: &Response, close: bool) -> Result<()> {
+    let body = serde_json::to_string(&response.body)?;
+    write!(
+        writer,
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n",
+        response.status,
+        reason_phrase(response.status),
+        body.len()
+    )?;
+    if close {
+        write!(writer, "Connection: close\r\n")?;
+    }
+    write!(writer, "\r\n{}", body)?;
+    writer.flush()?;
+    Ok(())
+}
+
+fn parse_body<'a, T: Deserialize<'a>>(body: &'a [u8]) -> std::result::Result<T, Response> {
+    serde_json::from_slice(body).map_err(|e| Response::error(400, "badRequest", e))
+}
+
+/// Routes a request. Returns the response, and whether the server should shut down.
+fn handle(server: &Server, request: Request) -> (Response, bool) {
+    if !server.authorized(request.token.as_deref()) {
+        return (
+            Response::error(401, "unauthorized", "missing or wrong bearer token"),
+            false,
+        );
+    }
+    let response = match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/health") => Response::ok(json!({
+            "status": "ok",
+            "profiles": server.profile_names(),
+        })),
+        ("GET", "/status") => Response::ok(server.status()),
+        ("POST", "/twoslash") => match parse_body::<TwoslashRequest>(&request.body) {
+            Ok(TwoslashRequest {
+                code,
+                options,
+                profile,
+                timeout_ms,
+            }) => match server.twoslash(None, code, options, profile.as_deref(), timeout_ms) {
+                Ok(twoslash_result) => Response::ok(json!(twoslash_result)),
+                Err(failure) => Response::failure(failure),
+            },
+            Err(response) => response,
+        },
+        ("POST", "/batch") => match parse_body::<BatchRequest>(&request.body) {
+            Ok(BatchRequest {
+                snippets,
+                profile,
+                timeout_ms,
+            }) => Response::ok(json!(server.batch(
+                None,
+                snippets,
+                profile.as_deref(),
+                timeout_ms
+            ))),
+            Err(response) => response,
+        },
+        ("POST", "/sessions/open") => match parse_body::<OpenSessionRequest>(&request.body) {
+            Ok(OpenSessionRequest {
+                session,
+                code,
+                options,
+                profile,
+                timeout_ms,
+            }) => {
+                let result = server.open_session(
+                    None,
+                    session,
+                    code,
+                    options,
+                    profile.as_deref(),
+                    timeout_ms,
+                );
+                match result {
+                    Ok(twoslash_result) => Response::ok(json!(twoslash_result)),
+                    Err(failure) => Response::failure(failure),
+                }
+            }
+            Err(response) => response,
+        },
+        ("POST", "/sessions/edit") => match parse_body::<EditSessionRequest>(&request.body) {
+            Ok(EditSessionRequest {
+                session,
+                edits,
+                timeout_ms,
+            }) => match server.edit_session(None, &session, &edits, timeout_ms) {
+                Ok(twoslash_result) => Response::ok(json!(twoslash_result)),
+                Err(failure) => Response::failure(failure),
+            },
+            Err(response) => response,
+        },
+        ("POST", "/sessions/close") => match parse_body::<CloseSessionRequest>(&request.body) {
+            Ok(CloseSessionRequest { session }) => {
+                Response::ok(json!(server.close_session(&session)))
+            }
+            Err(response) => response,
+        },
+        ("POST", "/shutdown") => match parse_body::<ShutdownRequest>(&request.body) {
+            Ok(ShutdownRequest { uuid }) if server.is_uuid(&uuid) => {
+                return (Response::ok(json!({})), true)
+            }
+            Ok(_) => Response::error(403, "badShutdown", "wrong server uuid"),
+            Err(response) => response,
+        },
+        (
+            _,
+            "/health" | "/status" | "/twoslash" | "/batch" | "/sessions/open" | "/sessions/edit"
+            | "/sessions/close" | "/shutdown",
+        ) => Response::error(405, "badRequest", "method not allowed"),
+        (_, path) => Response::error(404, "notFound", format!("no such endpoint {}", path)),
+    };
+    (response, false)
+}
+
+/// Serves HTTP requests from one connection until the client hangs up or asks the server to shut
+/// down. Returns whether the client asked the server to shut down.
+pub fn serve_connection(server: &Server, reader: impl Read, mut writer: impl Write) -> bool {
+    let mut reader = BufReader::new(reader);
+    loop {
+        let (response, close, shutdown) = match read_request(&mut reader) {
+            Ok(Incoming::Request(request)) => {
+                let close = request.close;
+                let (response, shutdown) = handle(server, request);
+                (response, close, shutdown)
+            }
+            // We can't tell where a malformed request ends, so don't try to read another.
+            Ok(Incoming::Malformed(response)) => (response, true, false),
+            // The client hung up, or the connection broke.
+            Ok(Incoming::Closed) | Err(_) => return false,
+        };
+
+        let close = close || shutdown;
+        if write_response(&mut writer, &response, close).is_err() || close {
+            return shutdown;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{read_request, Incoming, MAX_BODY, MAX_HEADERS, MAX_LINE};
+
+    fn parse(request: &str) -> Incoming {
+        read_request(&mut request.as_bytes()).unwrap()
+    }
+
+    fn malformed_status(request: &str) -> u16 {
+        match parse(request) {
+            Incoming::Malformed(response) => response.status,
+            Incoming::Request(_) => panic!("parsed a malformed request"),
+            Incoming::Closed => panic!("read a malformed request as a hang-up"),
+        }
+    }
+
+    #[test]
+    fn test_parses_requests() {
+        let request = "POST /twoslash HTTP/1.1\r\nAuthorization: Bearer abc\r\n\
+                       Content-Length: 2\r\n\r\n{}GET /health HTTP/1.1\r\n\r\n";
+        let mut reader = request.as_bytes();
+        match read_request(&mut reader).unwrap() {
+            Incoming::Request(request) => {
+                assert_eq!(request.method, "POST");
+                assert_eq!(request.path, "/twoslash");
+                assert_eq!(request.body, b"{}");
+                assert_eq!(request.token.as_deref(), Some("abc"));
+                assert!(!request.close);
+            }
+            _ => panic!("expected a request"),
+        }
+        match read_request(&mut reader).unwrap() {
+            Incoming::Request(request) => {
+                assert_eq!(request.path, "/health");
+                assert!(request.body.is_empty());
+            }
+            _ => panic!("expected a request"),
+        }
+        assert!(matches!(
+            read_request(&mut reader).unwrap(),
+            Incoming::Closed
+        ));
+    }
+
+    #[test]
+    fn test_closes_http_1_0_connections() {
+        match parse("GET /health HTTP/1.0\r\n\r\n") {
+            Incoming::Request(request) => assert!(request.close),
+            _ => panic!("expected a request"),
+        }
+        match parse("GET /health HTTP/1.0\r\nConnection: keep-alive\r\n\r\n") {
+            Incoming::Request(request) => assert!(!request.close),
+            _ => panic!("expected a request"),
+        }
+    }
+
+    #[test]
+    fn test_rejects_malformed_requests() {
+        assert_eq!(malformed_status("GET\r\n\r\n"), 400);
+        assert_eq!(malformed_status("GET / HTTP/1.1\r\nno colon\r\n\r\n"), 400);
+        assert_eq!(
+            malformed_status("POST / HTTP/1.1\r\nContent-Length: lots\r\n\r\n"),
+            400
+        );
+        assert_eq!(
+            malformed_status("POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n"),
+            411
+        );
+        let request = format!(
+            "POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+            MAX_BODY + 1
+        );
+        assert_eq!(malformed_status(&request), 413);
+    }
+
+    #[test]
+    fn test_limits_headers() {
+        let request = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(MAX_LINE));
+        assert_eq!(malformed_status(&request), 414);
+
+        let request = format!("GET / HTTP/1.1\r\nX: {}\r\n\r\n", "a".repeat(MAX_LINE));
+        assert_eq!(malformed_status(&request), 431);
+
+        let headers = "X: y\r\n".repeat(MAX_HEADERS + 1);
+        let request = format!("GET / HTTP/1.1\r\n{}\r\n", headers);
+        assert_eq!(malformed_status(&request), 431);
+
+        let headers = "X: y\r\n".repeat(MAX_HEADERS);
+        let request = format!("GET / HTTP/1.1\r\n{}\r\n", headers);
+        assert!(matches!(parse(&request), Incoming::Request(_)));
+    }
+
+    #[test]
+    fn test_treats_a_cut_off_request_as_a_hang_up() {
+        assert!(matches!(parse(""), Incoming::Closed));
+        assert!(matches!(
+            parse("GET / HTTP/1.1\r\nX: y\r\n"),
+            Incoming::Closed
+        ));
+    }
+}
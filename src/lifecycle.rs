@@ -0,0 +1,359 @@
+//! The `server` subcommands, which start, stop and keep track of servers in the background:
+//!
+//! - `rust-twoslash server start` starts a server with the settings in the environment, waits
+//!   until it is ready, and prints its UUID.
+//! - `rust-twoslash server stop <uuid>...` (or `--all`) shuts servers down.
+//! - `rust-twoslash server list` prints the servers that are running, as JSON.
+//! - `rust-twoslash server status <uuid>` prints a server's status, as JSON.
+//!
+//! Every server registers itself in a runtime directory that only the current user can read,
+//! with a record of its pid, address and token. Records of servers that have died are pruned
+//! whenever servers are listed.
+
+use std::fs::{self, DirBuilder, File};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+use std::os::unix::net::UnixStream;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::auth;
+use crate::protocol::{self, Framing};
+
+/// What a running server leaves in the runtime directory, so clients can find it.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Record {
+    pub uuid: String,
+    pub pid: u32,
+    /// How the server is reached: `tcp`, `unix` or `http`.
+    pub transport: String,
+    /// A TCP address, or the path of a Unix socket.
+    pub address: String,
+    pub token: Option<String>,
+    /// When the server started, in seconds since the Unix epoch.
+    pub started_at: u64,
+}
+
+/// The directory servers register themselves in: `$TWOSLASH_RUNTIME_DIR`, or a `rust-twoslash`
+/// directory in `$XDG_RUNTIME_DIR` or else the temporary directory. It is created if needed, and
+/// must belong to, and only be accessible to, the current user, since records hold tokens.
+pub fn runtime_dir() -> Result<PathBuf> {
+    let dir = match std::env::var_os("TWOSLASH_RUNTIME_DIR") {
+        Some(dir) => PathBuf::from(dir),
+        None => match std::env::var_os("XDG_RUNTIME_DIR") {
+            Some(dir) => PathBuf::from(dir).join("rust-twoslash"),
+            None => std::env::temp_dir().join(format!("rust-twoslash-{}", uid())),
+        },
+    };
+    DirBuilder::new().recursive(true).mode(0o700).create(&dir)?;
+
+    let metadata = fs::metadata(&dir)?;
+    if metadata.uid() != uid() {
+        anyhow::bail!("{} belongs to another user", dir.display());
+    }
+    if metadata.permissions().mode() & 0o077 != 0 {
+        anyhow::bail!("{} is accessible to other users", dir.display());
+    }
+    Ok(dir)
+}
+
+/// The id of the user running this process.
+fn uid() -> u32 {
+    // SAFETY: `getuid` has no preconditions, and always succeeds.
+    unsafe { libc::getuid() }
+}
+
+fn record_path(dir: &Path, uuid: &str) -> PathBuf {
+    dir.join(format!("{}.json", uuid))
+}
+
+fn log_path(dir: &Path, uuid: &str) -> PathBuf {
+    dir.join(format!("{}.log", uuid))
+}
+
+/// A server's record, which is removed when the server shuts down.
+pub struct Registration {
+    path: PathBuf,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Records a server as running in the runtime directory.
+pub fn register(
+    transport: &str,
+    address: String,
+    uuid: &str,
+    token: Option<&str>,
+) -> Result<Registration> {
+    let record = Record {
+        uuid: uuid.to_string(),
+        pid: std::process::id(),
+        transport: transport.to_string(),
+        address,
+        token: token.map(str::to_string),
+        started_at: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs()),
+    };
+    let path = record_path(&runtime_dir()?, uuid);
+    // Write the record whole, so that nobody reads half of one.
+    let partial = path.with_extension("json.tmp");
+    fs::write(&partial, serde_json::to_string(&record)?)?;
+    fs::rename(&partial, &path)?;
+    Ok(Registration { path })
+}
+
+/// Whether the process `pid` is still running.
+pub fn alive(pid: u32) -> bool {
+    // Non-positive pids name process groups rather than processes.
+    let pid = match libc::pid_t::try_from(pid) {
+        Ok(pid) if pid > 0 => pid,
+        _ => return false,
+    };
+    // Signal 0 only checks whether the process could be signalled. It fails with `EPERM` for
+    // processes that exist but belong to someone else, and with `ESRCH` for those that don't.
+    // SAFETY: `kill` with signal 0 has no effect on the process.
+    match unsafe { libc::kill(pid, 0) } {
+        0 => true,
+        _ => std::io::Error::last_os_error().raw_os_error() != Some(libc::ESRCH),
+    }
+}
+
+/// The records of running servers. Records of servers that have died are removed.
+fn records() -> Result<Vec<Record>> {
+    let dir = runtime_dir()?;
+    let mut records = vec![];
+    for entry in fs::read_dir(&dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(|ext| ext.to_str()) != Some("json") {
+            continue;
+        }
+        let record = fs::read_to_string(&path)
+            .ok()
+            .and_then(|contents| serde_json::from_str::<Record>(&contents).ok());
+        match record {
+            Some(record) if alive(record.pid) => records.push(record),
+            _ => {
+                let _ = fs::remove_file(&path);
+            }
+        }
+    }
+    records.sort_by_key(|record| record.started_at);
+    Ok(records)
+}
+
+fn find(uuid: &str) -> Result<Record> {
+    records()?
+        .into_iter()
+        .find(|record| record.uuid == uuid)
+        .with_context(|| format!("no server {} is running", uuid))
+}
+
+/// Sends a request envelope to a server, and returns the result.
+fn request(record: &Record, body: serde_json::Value) -> Result<serde_json::Value> {
+    if record.transport == "http" {
+        return http_request(record, &body);
+    }
+    let mut envelope = body;
+    envelope["version"] = json!(protocol::VERSION);
+    envelope["token"] = json!(record.token);
+    let frame = envelope.to_string();
+
+    let response = match record.transport.as_str() {
+        "tcp" => {
+            let mut stream = TcpStream::connect(&record.address)?;
+            protocol::write(&mut stream, Framing::Tagged, &frame)?;
+            protocol::read(&mut stream)?.payload?
+        }
+        "unix" => {
+            let mut stream = UnixStream::connect(&record.address)?;
+            protocol::write(&mut stream, Framing::Tagged, &frame)?;
+            protocol::read(&mut stream)?.payload?
+        }
+        other => anyhow::bail!("unknown transport {:?}", other),
+    };
+
+    let mut response: serde_json::Value = serde_json::from_str(&response)?;
+    match response.get("error") {
+        Some(error) => Err(error_message(error)),
+        None => Ok(response["result"].take()),
+    }
+}
+
+/// Sends the HTTP equivalent of a request envelope to a server in HTTP mode.
+fn http_request(record: &Record, body: &serde_json::Value) -> Result<serde_json::Value> {
+    let (method, path, payload) = match body["kind"].as_str() {
+        Some("status") => ("GET", "/status", String::new()),
+        Some("shutdown") => (
+            "POST",
+            "/shutdown",
+            json!({ "uuid": body["uuid"] }).to_string(),
+        ),
+        kind => anyhow::bail!("no HTTP endpoint for {:?}", kind),
+    };
+
+    let mut stream = TcpStream::connect(&record.address)?;
+    write!(
+        stream,
+        "{} {} HTTP/1.1\r\nHost: {}\r\n",
+        method, path, record.address
+    )?;
+    if let Some(token) = &record.token {
+        write!(stream, "Authorization: Bearer {}\r\n", token)?;
+    }
+    write!(
+        stream,
+        "Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+        payload.len(),
+        payload
+    )?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    let (head, body) = response
+        .split_once("\r\n\r\n")
+        .context("malformed HTTP response")?;
+    let body: serde_json::Value = serde_json::from_str(body)?;
+    match head.split_whitespace().nth(1) {
+        Some("200") => Ok(body),
+        _ => Err(error_message(&body["error"])),
+    }
+}
+
+/// Turns the `error` of a response into an error of ours.
+fn error_message(error: &serde_json::Value) -> anyhow::Error {
+    anyhow::Error::msg(format!(
+        "{}: {}",
+        error["kind"].as_str().unwrap_or("unknown"),
+        error["message"].as_str().unwrap_or_default()
+    ))
+}
+
+/// A random (version 4) UUID.
+fn new_uuid() -> Result<String> {
+    let mut bytes = [0; 16];
+    auth::random_bytes(&mut bytes)?;
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    Ok(format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    ))
+}
+
+/// Starts a server in the background, with the settings in our environment, and returns its
+/// UUID once it is ready.
+fn start() -> Result<String> {
+    let dir = runtime_dir()?;
+    let uuid = new_uuid()?;
+    let log = File::create(log_path(&dir, &uuid))?;
+
+    let mut child = Command::new(std::env::current_exe()?)
+        .env("TWOSLASH_SERVER_UUID", &uuid)
+        .env_remove("TWOSLASH_TOKEN_FILE")
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(log)
+        // Don't take the server down with whatever started it, like a terminal's Ctrl-C.
+        .process_group(0)
+        .spawn()?;
+
+    // The server announces itself once it is ready, having registered itself first.
+    let mut announcement = String::new();
+    BufReader::new(child.stdout.take().unwrap()).read_line(&mut announcement)?;
+    if announcement.is_empty() {
+        let status = child.wait()?;
+        anyhow::bail!(
+            "the server exited ({}) before it was ready; see {}",
+            status,
+            log_path(&dir, &uuid).display()
+        );
+    }
+    Ok(uuid)
+}
+
+/// Shuts down a server. Its log is kept, for diagnosing what it did.
+fn stop(record: &Record) -> Result<()> {
+    request(record, json!({ "kind": "shutdown", "uuid": record.uuid }))?;
+    Ok(())
+}
+
+/// Fails with every server that could not be stopped, and why, if there were any. Servers are
+/// stopped one by one, and one that doesn't answer mustn't keep the rest running.
+fn report_failures(failures: Vec<(&str, anyhow::Error)>) -> Result<()> {
+    if failures.is_empty() {
+        return Ok(());
+    }
+    let failures: Vec<String> = failures
+        .iter()
+        .map(|(uuid, e)| format!("{}: {:#}", uuid, e))
+        .collect();
+    anyhow::bail!(
+        "could not stop {} server(s):\n{}",
+        failures.len(),
+        failures.join("\n")
+    )
+}
+
+/// Runs a `server` subcommand with the given arguments.
+pub fn run(args: &[String]) -> Result<()> {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let mut stdout = std::io::stdout();
+    match args.as_slice() {
+        ["start"] => writeln!(stdout, "{}", start()?)?,
+        ["stop", "--all"] => {
+            let records = records()?;
+            let failures = records
+                .iter()
+                .filter_map(|record| Some((record.uuid.as_str(), stop(record).err()?)))
+                .collect();
+            report_failures(failures)?;
+        }
+        ["stop", uuids @ ..] if !uuids.is_empty() => {
+            let failures = uuids
+                .iter()
+                .filter_map(|&uuid| Some((uuid, find(uuid).and_then(|r| stop(&r)).err()?)))
+                .collect();
+            report_failures(failures)?;
+        }
+        ["list"] => writeln!(stdout, "{}", serde_json::to_string_pretty(&records()?)?)?,
+        ["status", uuid] => {
+            let status = request(&find(uuid)?, json!({ "kind": "status" }))?;
+            writeln!(stdout, "{}", serde_json::to_string_pretty(&status)?)?;
+        }
+        _ => anyhow::bail!(
+            "usage: rust-twoslash server start | stop <uuid>... | stop --all | list | status <uuid>"
+        ),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::alive;
+
+    #[test]
+    fn test_checks_liveness() {
+        assert!(alive(std::process::id()));
+        // Larger than any pid the kernel hands out.
+        assert!(!alive(i32::MAX as u32));
+        assert!(!alive(0));
+    }
+}
@@ -0,0 +1,273 @@
+//! Structured logs, so that slow or failing snippets can be diagnosed after the fact.
+//!
+//! Each event has a level, a message and JSON fields. Events are written as one line each, to
+//! stderr or to `TWOSLASH_LOG_FILE`, either for humans or as JSON (`TWOSLASH_LOG_FORMAT=json`).
+//! `TWOSLASH_LOG` sets the least severe level that is written: `error`, `warn`, `info` (the
+//! default), `debug`, or `off`.
+
+use std::any::Any;
+use std::backtrace::Backtrace;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::panic;
+use std::sync::{Mutex, PoisonError};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use serde_json::json;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl Level {
+    fn name(self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Format {
+    Human,
+    Json,
+}
+
+struct Logger {
+    /// The least severe level that is written, or `None` if nothing is.
+    level: Option<Level>,
+    format: Format,
+    /// Where events go, if not to stderr.
+    file: Option<File>,
+}
+
+impl Default for Logger {
+    fn default() -> Self {
+        Logger {
+            level: Some(Level::Info),
+            format: Format::Human,
+            file: None,
+        }
+    }
+}
+
+impl Logger {
+    fn from_env() -> Result<Self> {
+        let level = match std::env::var("TWOSLASH_LOG").as_deref() {
+            Err(_) | Ok("info") => Some(Level::Info),
+            Ok("error") => Some(Level::Error),
+            Ok("warn") => Some(Level::Warn),
+            Ok("debug") => Some(Level::Debug),
+            Ok("off") => None,
+            Ok(other) => anyhow::bail!("unknown TWOSLASH_LOG level {:?}", other),
+        };
+        let format = match std::env::var("TWOSLASH_LOG_FORMAT").as_deref() {
+            Err(_) | Ok("human") => Format::Human,
+            Ok("json") => Format::Json,
+            Ok(other) => anyhow::bail!("unknown TWOSLASH_LOG_FORMAT {:?}", other),
+        };
+        let file = match std::env::var_os("TWOSLASH_LOG_FILE") {
+            Some(path) => Some(OpenOptions::new().create(true).append(true).open(path)?),
+            None => None,
+        };
+        Ok(Logger {
+            level,
+            format,
+            file,
+        })
+    }
+}
+
+/// Events logged before `init` go to stderr, with the default settings.
+static LOGGER: Mutex<Option<Logger>> = Mutex::new(None);
+
+/// Configures logging from the environment, and logs panics, with their backtraces, from now on.
+pub fn init() -> Result<()> {
+    let logger = Logger::from_env()?;
+    *LOGGER.lock().unwrap_or_else(PoisonError::into_inner) = Some(logger);
+
+    panic::set_hook(Box::new(|info| {
+        error(
+            "panic",
+            json!({
+                "thread": thread::current().name(),
+                "panic": panic_message(info.payload()),
+                "location": info.location().map(ToString::to_string),
+                "backtrace": Backtrace::force_capture().to_string(),
+            }),
+        );
+    }));
+    Ok(())
+}
+
+/// The message a panic was started with.
+pub fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else if let Some(cancelled) = payload.downcast_ref::<ra_ide::Cancelled>() {
+        // rust-analyzer cancels queries by panicking with a `Cancelled` payload.
+        cancelled.to_string()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+/// Formats a time as an RFC 3339 timestamp in UTC, with milliseconds.
+fn timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, secs_of_day) = (secs / 86400, secs % 86400);
+
+    // Converts days since the epoch to a date in the proleptic Gregorian calendar; see
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days.
+    let z = days + 719468;
+    let era = z / 146097;
+    let day_of_era = z % 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+/// Formats an event as a line, without the trailing newline.
+fn format_event(
+    format: Format,
+    time: SystemTime,
+    level: Level,
+    message: &str,
+    fields: serde_json::Value,
+) -> String {
+    match format {
+        Format::Json => {
+            let mut event = json!({
+                "timestamp": timestamp(time),
+                "level": level.name(),
+                "message": message,
+            });
+            for (name, value) in fields.as_object().into_iter().flatten() {
+                event[name.as_str()] = value.clone();
+            }
+            event.to_string()
+        }
+        Format::Human => {
+            let mut line = format!(
+                "{} {:5} {}",
+                timestamp(time),
+                level.name().to_uppercase(),
+                message
+            );
+            // Multi-line values, like backtraces, are easier to read as they are, after the line.
+            let mut blocks = String::new();
+            for (name, value) in fields.as_object().into_iter().flatten() {
+                match value.as_str() {
+                    Some(text) if text.contains('\n') => {
+                        blocks.push_str(&format!("\n  {}:\n", name));
+                        for text_line in text.trim_end().lines() {
+                            blocks.push_str(&format!("    {}\n", text_line));
+                        }
+                    }
+                    _ => line.push_str(&format!(" {}={}", name, value)),
+                }
+            }
+            line + blocks.trim_end()
+        }
+    }
+}
+
+/// Logs an event with `fields`, which should be a JSON object, if `level` is enabled.
+pub fn log(level: Level, message: &str, fields: serde_json::Value) {
+    let mut logger = LOGGER.lock().unwrap_or_else(PoisonError::into_inner);
+    let logger = logger.get_or_insert_with(Logger::default);
+    if logger.level.is_none_or(|enabled| level > enabled) {
+        return;
+    }
+    let line = format_event(logger.format, SystemTime::now(), level, message, fields) + "\n";
+    // Logging must never take the server down, so failures to write are ignored.
+    let _ = match &mut logger.file {
+        Some(file) => file.write_all(line.as_bytes()),
+        None => std::io::stderr().write_all(line.as_bytes()),
+    };
+}
+
+pub fn error(message: &str, fields: serde_json::Value) {
+    log(Level::Error, message, fields)
+}
+
+pub fn warn(message: &str, fields: serde_json::Value) {
+    log(Level::Warn, message, fields)
+}
+
+pub fn info(message: &str, fields: serde_json::Value) {
+    log(Level::Info, message, fields)
+}
+
+pub fn debug(message: &str, fields: serde_json::Value) {
+    log(Level::Debug, message, fields)
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use serde_json::json;
+
+    use super::{format_event, timestamp, Format, Level};
+
+    #[test]
+    fn test_timestamps() {
+        assert_eq!(timestamp(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+        let time = UNIX_EPOCH + Duration::from_millis(1_709_210_096_789);
+        assert_eq!(timestamp(time), "2024-02-29T12:34:56.789Z");
+    }
+
+    #[test]
+    fn test_formats() {
+        let fields = json!({ "id": "abc", "durationMs": 1.5 });
+        assert_eq!(
+            format_event(
+                Format::Human,
+                UNIX_EPOCH,
+                Level::Info,
+                "twoslash",
+                fields.clone()
+            ),
+            "1970-01-01T00:00:00.000Z INFO  twoslash durationMs=1.5 id=\"abc\""
+        );
+        let line = format_event(Format::Json, UNIX_EPOCH, Level::Warn, "twoslash", fields);
+        let event: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(event["level"], "warn");
+        assert_eq!(event["message"], "twoslash");
+        assert_eq!(event["id"], "abc");
+
+        let backtrace = json!({ "backtrace": "0: main\n1: start\n" });
+        assert_eq!(
+            format_event(Format::Human, UNIX_EPOCH, Level::Error, "panic", backtrace),
+            "1970-01-01T00:00:00.000Z ERROR panic\n  backtrace:\n    0: main\n    1: start"
+        );
+    }
+}
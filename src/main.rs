@@ -1,75 +1,257 @@
+mod auth;
+mod cache;
+mod cancel;
+mod cargo;
+mod directives;
+mod http;
+mod lifecycle;
+mod logging;
+mod options;
+mod pool;
+mod proc_macro;
+mod profiles;
 mod project;
 mod protocol;
 mod query_parser;
+mod runner;
+mod rustdoc;
+mod server;
+mod session;
+mod stats;
+mod transport;
 mod twoslash;
+mod wrap;
 
-use project::{Project, ProjectSettings};
+// Servers rely on Unix sockets, file modes, signals and /dev/urandom throughout.
+#[cfg(not(unix))]
+compile_error!("rust-twoslash only supports Unix-like systems");
+
+use cache::DiskCache;
+use cancel::Cancel;
+use options::SnippetOptions;
+use profiles::Profile;
+use project::{Project, ProjectKind, ProjectSettings};
+use server::{Server, ServerSettings};
 
 use anyhow::Result;
 use std::io::{Read, Write};
 use std::net::TcpListener;
+use std::path::PathBuf;
+use std::time::Duration;
 use tempfile::TempDir;
 
+/// How long a server lets an analysis take by default.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+/// How many results a server keeps in memory by default.
+const DEFAULT_CACHE_SIZE: usize = 1000;
+/// How many sessions a server keeps open by default.
+const DEFAULT_MAX_SESSIONS: usize = 4;
+
 fn main() -> Result<()> {
-    let make_cargo_project = std::env::var("TWOSLASH_USE_CARGO").unwrap_or_default() == "1";
+    if std::env::args().nth(1).as_deref() == Some(proc_macro::SERVER_ARG) {
+        // rust-analyzer spawns us as its proc-macro server when it loads a cargo project.
+        return proc_macro::run_server();
+    }
+    if std::env::args().nth(1).as_deref() == Some("server") {
+        let args: Vec<String> = std::env::args().skip(2).collect();
+        return lifecycle::run(&args);
+    }
+    logging::init()?;
+
+    let kind = if std::env::var("TWOSLASH_USE_CARGO").unwrap_or_default() == "1" {
+        ProjectKind::Cargo
+    } else if std::env::var("TWOSLASH_USE_SYSROOT").unwrap_or_default() == "1" {
+        ProjectKind::Sysroot
+    } else {
+        ProjectKind::SingleFile
+    };
+    let use_clippy = std::env::var("TWOSLASH_USE_CLIPPY").unwrap_or_default() == "1";
+    let with_proc_macros = std::env::var("TWOSLASH_PROC_MACROS").unwrap_or_default() == "1";
+    let dependencies = std::env::var("TWOSLASH_DEPENDENCIES").unwrap_or_default();
     let tmpdir = TempDir::new()?;
     let default_project_name = "twoslash-rust-project";
     let mut project_settings = ProjectSettings {
-        make_cargo_project,
-        project_name: default_project_name,
-        tmpdir: &tmpdir,
+        kind,
+        project_name: default_project_name.to_string(),
+        dir: tmpdir.path().to_path_buf(),
+        use_clippy,
+        with_proc_macros,
+        dependencies,
     };
 
     if let Ok(server_uuid) = std::env::var("TWOSLASH_SERVER_UUID") {
-        let project_name =
-            std::env::var("TWOSLASH_PROJECT_NAME").unwrap_or(default_project_name.to_string());
-        project_settings.project_name = &project_name;
+        if let Ok(project_name) = std::env::var("TWOSLASH_PROJECT_NAME") {
+            project_settings.project_name = project_name;
+        }
 
         // We have been asked to start up in server mode.
         //
         // The server "protocol":
         //
         // 1. <exec> TWOSLASH_SERVER_UUID=00uuid server start.
-        // 2. Server writes "<server addr>\n" to stdout.
+        // 2. Server writes "<server addr> <token>\n" to stdout, or just the address if
+        //    TWOSLASH_TOKEN_FILE names a file to write the token to instead. Every request must
+        //    carry the token; see `auth`. With TWOSLASH_TRANSPORT=unix, the address
+        //    is the path of a Unix socket that only the server's user may connect to. With
+        //    TWOSLASH_TRANSPORT=stdio, nothing is written; frames go over stdin and stdout instead.
+        //    With TWOSLASH_TRANSPORT=http, the server speaks HTTP rather than frames; see `http`.
         // 3. The server is now ready:
         //
         // | client |                            | server @ 0.0.0.0:port |
         //
-        //   <code>  --------------------------->
-        //           <---------------------------  <json twoslash result>
+        //   <request>  ------------------------>
+        //              <------------------------  <response>
         //                      ...
         //
-        //  "Shutdown 00uuid" ------------------>  <server shutdown>
+        //  <shutdown request> ----------------->  <server shutdown>
         //
-        let shutdown_message = format!("Shutdown {}", server_uuid);
+        // Connections stay open for as many requests as the client likes, and requests may be
+        // pipelined; responses come back in the same order.
+        //
+        // Requests and responses are JSON envelopes; see `protocol::Request` and
+        // `protocol::Response`. For compatibility, a request may also be plain code, which is
+        // answered with a bare twoslash result, or "Shutdown 00uuid".
 
-        // Create the project that we will reuse between connections to the socket.
-        let mut project = Project::scaffold(project_settings)?;
+        // How many snippets may be analyzed at once. Each worker has its own project, and so its
+        // own rust-analyzer database.
+        let workers = std::env::var("TWOSLASH_WORKERS")
+            .ok()
+            .and_then(|n| n.parse().ok())
+            .unwrap_or(1);
+        // Requests may pick a profile to be analyzed with; the settings above are the default.
+        let profiles = match std::env::var_os("TWOSLASH_PROFILES") {
+            Some(path) => profiles::load(path.as_ref(), &project_settings, workers)?,
+            None => vec![Profile {
+                name: profiles::DEFAULT_PROFILE.to_string(),
+                settings: project_settings,
+                edition: None,
+                workers,
+            }],
+        };
+        // How long an analysis may take before it is cancelled, unless the request says otherwise.
+        // 0 means there is no limit.
+        let timeout = match std::env::var("TWOSLASH_TIMEOUT_MS")
+            .ok()
+            .and_then(|ms| ms.parse().ok())
+        {
+            Some(0) => None,
+            Some(ms) => Some(Duration::from_millis(ms)),
+            None => Some(DEFAULT_TIMEOUT),
+        };
+        let transport_kind =
+            std::env::var("TWOSLASH_TRANSPORT").unwrap_or_else(|_| "tcp".to_string());
 
-        // Start the server side of the socket.
-        let server = TcpListener::bind("127.0.0.1:0")?;
-        std::io::stdout().write_fmt(format_args!("{}\n", server.local_addr()?))?;
-        std::io::stdout().flush()?;
+        // Clients must present a secret with every request. A stdio server's only client is
+        // whoever started it, so it needs none. Only the server's user can connect to a Unix
+        // socket, so it may do without one for clients that can't send it; TCP ports are open to
+        // every local user, so they always need one.
+        let no_auth = std::env::var("TWOSLASH_AUTH").unwrap_or_default() == "0";
+        if no_auth && transport_kind != "unix" && transport_kind != "stdio" {
+            anyhow::bail!("TWOSLASH_AUTH=0 needs TWOSLASH_TRANSPORT=unix");
+        }
+        let token = match transport_kind == "stdio" || no_auth {
+            true => None,
+            false => Some(auth::generate_token()?),
+        };
+        let token_file = std::env::var_os("TWOSLASH_TOKEN_FILE");
+        if let (Some(token), Some(path)) = (&token, &token_file) {
+            auth::write_token_file(path.as_ref(), token)?;
+        }
+        // Registers the server in the runtime directory, so `server` subcommands can find it,
+        // then tells the client where to connect, and with what token unless it is in a file.
+        let uuid = server_uuid.clone();
+        let announce = |address: &dyn std::fmt::Display| -> Result<lifecycle::Registration> {
+            let registration = lifecycle::register(
+                &transport_kind,
+                address.to_string(),
+                &uuid,
+                token.as_deref(),
+            )?;
+            let mut stdout = std::io::stdout();
+            match (&token, &token_file) {
+                (Some(token), None) => writeln!(stdout, "{} {}", address, token)?,
+                _ => writeln!(stdout, "{}", address)?,
+            }
+            stdout.flush()?;
+            logging::info(
+                "listening",
+                serde_json::json!({
+                    "uuid": uuid,
+                    "pid": std::process::id(),
+                    "transport": transport_kind,
+                    "address": address.to_string(),
+                }),
+            );
+            Ok(registration)
+        };
 
-        for stream in server.incoming() {
-            std::io::stderr().flush()?;
-            let mut stream = stream?;
-            let message = protocol::read(&stream)?;
+        // How many results to keep, so that unchanged snippets are answered without analysis.
+        let cache_size = std::env::var("TWOSLASH_CACHE_SIZE")
+            .ok()
+            .and_then(|n| n.parse().ok())
+            .unwrap_or(DEFAULT_CACHE_SIZE);
+        // Each session has its own project, so they are limited like workers are.
+        let max_sessions = std::env::var("TWOSLASH_MAX_SESSIONS")
+            .ok()
+            .and_then(|n| n.parse().ok())
+            .unwrap_or(DEFAULT_MAX_SESSIONS);
+        // Servers shut themselves down after going this long without requests, if set, so that
+        // servers whose clients forgot about them don't hold on to memory forever.
+        let idle_timeout = std::env::var("TWOSLASH_IDLE_TIMEOUT_MS")
+            .ok()
+            .and_then(|ms| ms.parse::<u64>().ok())
+            .filter(|&ms| ms > 0)
+            .map(Duration::from_millis);
+        // Or once the process that wanted them exits.
+        let parent = std::env::var("TWOSLASH_PARENT_PID")
+            .ok()
+            .and_then(|pid| pid.parse().ok());
+        // A stdio server lives exactly as long as its client keeps stdin open, and nothing
+        // watches it otherwise; don't let these settings look like they do something.
+        if transport_kind == "stdio" && (idle_timeout.is_some() || parent.is_some()) {
+            anyhow::bail!(
+                "TWOSLASH_IDLE_TIMEOUT_MS and TWOSLASH_PARENT_PID don't apply to \
+                 TWOSLASH_TRANSPORT=stdio, which shuts down when stdin closes"
+            );
+        }
+        let server_settings = ServerSettings {
+            uuid: server_uuid,
+            token: token.clone(),
+            timeout,
+            cache_size,
+            max_sessions,
+            idle_timeout,
+            parent,
+        };
+        let server = Server::new(profiles, server_settings)?;
 
-            if message == shutdown_message {
-                break;
+        // Start the server side of the socket.
+        match transport_kind.as_str() {
+            "stdio" => transport::serve_stdio(server)?,
+            "unix" => {
+                let path = match std::env::var_os("TWOSLASH_SOCKET") {
+                    Some(path) => PathBuf::from(path),
+                    None => tmpdir.path().join("twoslash.sock"),
+                };
+                let listener = transport::bind_unix(&path)?;
+                let _registration = announce(&path.display())?;
+
+                transport::serve_unix(server, listener, &path)?;
             }
+            "http" => {
+                let listener = TcpListener::bind("127.0.0.1:0")?;
+                let _registration = announce(&listener.local_addr()?)?;
 
-            // The only other messages we permit via this "protocol" (if you can call it that, lol)
-            // are code that should be analyzed for twoslash-ing.
-            project = project.apply_change(message);
-            let twoslash_result = project.twoslasher()?;
+                transport::serve_http(server, listener)?;
+            }
+            "tcp" => {
+                let listener = TcpListener::bind("127.0.0.1:0")?;
+                let _registration = announce(&listener.local_addr()?)?;
 
-            protocol::write(&stream, &serde_json::to_string(&twoslash_result)?)?;
-            stream.flush()?;
+                transport::serve_tcp(server, listener)?;
+            }
+            other => anyhow::bail!("unknown transport {:?}", other),
         }
-
-        drop(server);
     } else {
         // We are being asked to run in one-off mode.
         let source = {
@@ -77,8 +259,29 @@ fn main() -> Result<()> {
             std::io::stdin().read_to_string(&mut buf)?;
             buf
         };
-        let project = Project::scaffold_with_code(project_settings, &source)?;
-        let twoslash_result = project.twoslasher()?;
+        let options = SnippetOptions::default();
+
+        // Results may be cached on disk, so that repeated builds skip unchanged snippets.
+        let cache = match std::env::var_os("TWOSLASH_CACHE_DIR") {
+            Some(dir) => Some(DiskCache::new(dir.into())?),
+            None => None,
+        };
+        let key = cache::key(
+            &source,
+            &options,
+            &project_settings,
+            &cache::toolchain_version(),
+        );
+        if let Some(cached) = cache.as_ref().and_then(|cache| cache.get(&key)) {
+            println!("{}", serde_json::to_string_pretty(&cached)?);
+            return Ok(());
+        }
+
+        let project = Project::scaffold_with_code(project_settings, &source, &options)?;
+        let twoslash_result = project.twoslasher(&Cancel::default())?;
+        if let Some(cache) = cache.filter(|_| cache::cacheable(&twoslash_result)) {
+            cache.insert(&key, &twoslash_result)?;
+        }
         println!("{}", serde_json::to_string_pretty(&twoslash_result)?);
     }
 
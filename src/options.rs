@@ -0,0 +1,63 @@
+//! Options that configure how a single snippet is analyzed. Clients pass these with each request;
+//! directives in the snippet itself take precedence over them.
+
+use ra_ide::Edition;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub enum EditionOption {
+    #[serde(rename = "2015")]
+    Edition2015,
+    #[serde(rename = "2018")]
+    Edition2018,
+    #[serde(rename = "2021")]
+    Edition2021,
+}
+
+impl From<EditionOption> for Edition {
+    fn from(edition: EditionOption) -> Self {
+        match edition {
+            EditionOption::Edition2015 => Edition::Edition2015,
+            EditionOption::Edition2018 => Edition::Edition2018,
+            EditionOption::Edition2021 => Edition::Edition2021,
+        }
+    }
+}
+
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum CutMode {
+    /// Only show the code between `// ---cut---` and `// ---cut-after---` markers.
+    #[default]
+    Markers,
+    /// Show all of the code, treating cut markers as ordinary comments.
+    None,
+}
+
+/// How offsets in the result are measured.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Encoding {
+    /// In bytes of UTF-8.
+    #[default]
+    Utf8,
+    /// In UTF-16 code units, like JavaScript strings.
+    Utf16,
+}
+
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct SnippetOptions {
+    /// The edition to analyze the snippet with. Defaults to the current edition.
+    pub edition: Option<EditionOption>,
+    pub cut: CutMode,
+    /// Names of `// @name: annotation` comments to extract from the snippet as tags.
+    pub custom_tags: Vec<String>,
+    pub encoding: Encoding,
+    /// Like a `// @clippy` directive.
+    pub clippy: Option<Vec<String>>,
+    /// Like a `// @run` directive.
+    pub run: bool,
+    /// Like a `// @rustdoc` directive.
+    pub rustdoc: Option<String>,
+}
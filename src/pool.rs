@@ -0,0 +1,257 @@
+//! A pool of workers that analyze snippets in parallel, each with its own project.
+
+use std::fs;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+
+use crate::cancel::{Cancel, Interrupted, Reason};
+use crate::logging;
+use crate::options::SnippetOptions;
+use crate::project::{Project, ProjectSettings};
+use crate::stats::{Phases, Stats};
+use crate::twoslash::TwoSlash;
+
+struct Job {
+    code: String,
+    options: SnippetOptions,
+    cancel: Cancel,
+    reply: Sender<Update>,
+}
+
+/// What a worker tells whoever is waiting on a job.
+enum Update {
+    /// A worker has taken the job off the queue, so its deadline starts now.
+    Started,
+    Finished((Result<TwoSlash>, Phases)),
+}
+
+/// How long to wait for a worker to notice that its analysis was cancelled before giving up on it.
+/// rust-analyzer only checks for cancellation between queries, so a single slow query can take a
+/// while to stop.
+const CANCEL_GRACE: Duration = Duration::from_secs(2);
+
+/// How often to check for deadlines and cancellation while waiting on a worker.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// How much stack workers get. rust-analyzer recurses deeply on some code, and overflowing the
+/// stack aborts the whole server rather than unwinding, so workers get more than the 8 MiB a main
+/// thread usually has.
+const WORKER_STACK_SIZE: usize = 32 * 1024 * 1024;
+
+struct Worker {
+    settings: ProjectSettings,
+    stats: Arc<Stats>,
+    /// The project we reuse between requests, or `None` if a request panicked while it was being
+    /// used, since its state can't be trusted anymore. It is rebuilt before the next request.
+    project: Option<Project>,
+}
+
+impl Worker {
+    /// Analyzes `code` with the worker's project, rebuilding the project first if a previous
+    /// request left it unusable. Panics are caught and reported as errors; the project is thrown
+    /// away if one happens. Also returns how long each phase of the analysis took.
+    fn twoslash(
+        &mut self,
+        code: String,
+        options: &SnippetOptions,
+        cancel: &Cancel,
+    ) -> (Result<TwoSlash>, Phases) {
+        let project = match self.project.take() {
+            Some(project) => project,
+            None => match Project::scaffold(self.settings.clone()) {
+                Ok(project) => project,
+                Err(e) => return (Err(e), vec![]),
+            },
+        };
+        if !cancel.attach(project.host()) {
+            cancel.detach();
+            self.project = Some(project);
+            let interrupted = Interrupted {
+                reason: cancel.reason(),
+                partial: None,
+            };
+            return (Err(interrupted.into()), vec![]);
+        }
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            let project = project.apply_change(code, options);
+            let twoslash_result = project.twoslasher(cancel);
+            (project, twoslash_result)
+        }));
+        cancel.detach();
+        match result {
+            Ok((project, twoslash_result)) => {
+                self.stats.record_phases(project.timings());
+                let phases = project.timings().phases();
+                self.project = Some(project);
+                let twoslash_result =
+                    twoslash_result.map_err(|e| match e.downcast::<Interrupted>() {
+                        Ok(interrupted) => Interrupted {
+                            reason: cancel.reason(),
+                            ..interrupted
+                        }
+                        .into(),
+                        Err(e) => e,
+                    });
+                (twoslash_result, phases)
+            }
+            // Cancellation that unwinds, rather than failing a query, leaves nothing to show for it.
+            Err(_) if cancel.reason().is_some() => {
+                let interrupted = Interrupted {
+                    reason: cancel.reason(),
+                    partial: None,
+                };
+                (Err(interrupted.into()), vec![])
+            }
+            Err(payload) => {
+                let message = format!("analysis panicked: {}", logging::panic_message(&*payload));
+                (Err(anyhow::Error::msg(message)), vec![])
+            }
+        }
+    }
+
+    fn run(mut self, jobs: Arc<Mutex<Receiver<Job>>>) {
+        loop {
+            // Only hold the lock while waiting for a job, so other workers can take the next one.
+            let job = match jobs.lock().unwrap().recv() {
+                Ok(job) => job,
+                // The pool was dropped.
+                Err(_) => return,
+            };
+            // The requester may have given up waiting, which is fine.
+            let _ = job.reply.send(Update::Started);
+            let result = self.twoslash(job.code, &job.options, &job.cancel);
+            let _ = job.reply.send(Update::Finished(result));
+        }
+    }
+}
+
+/// Dispatches snippets to whichever worker is free. Each worker owns a project (and so a
+/// rust-analyzer database, including its own copy of any sysroot data) in its own directory.
+pub struct Pool {
+    jobs: Option<Sender<Job>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl Pool {
+    /// Starts `size` workers, and waits until they have all scaffolded their projects. Workers
+    /// record how long each phase of their analyses takes in `stats`.
+    pub fn new(settings: &ProjectSettings, size: usize, stats: &Arc<Stats>) -> Result<Pool> {
+        let (jobs, receiver) = mpsc::channel();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let mut workers = vec![];
+        let mut ready = vec![];
+
+        for i in 0..size.max(1) {
+            let settings = ProjectSettings {
+                dir: settings.dir.join(format!("worker-{}", i)),
+                ..settings.clone()
+            };
+            fs::create_dir_all(&settings.dir)?;
+
+            let (ready_tx, ready_rx) = mpsc::channel();
+            let receiver = Arc::clone(&receiver);
+            let stats = Arc::clone(stats);
+            let worker = thread::Builder::new()
+                .name(format!("worker-{}", i))
+                .stack_size(WORKER_STACK_SIZE);
+            let handle = worker.spawn(move || match Project::scaffold(settings.clone()) {
+                Ok(project) => {
+                    let _ = ready_tx.send(Ok(()));
+                    let worker = Worker {
+                        settings,
+                        stats,
+                        project: Some(project),
+                    };
+                    worker.run(receiver);
+                }
+                Err(e) => {
+                    let _ = ready_tx.send(Err(e));
+                }
+            })?;
+            workers.push(handle);
+            ready.push(ready_rx);
+        }
+
+        let pool = Pool {
+            jobs: Some(jobs),
+            workers,
+        };
+        for ready in ready {
+            ready.recv()??;
+        }
+        Ok(pool)
+    }
+
+    /// Analyzes `code` on the next free worker, waiting for the result. If `timeout` passes first,
+    /// counting from when a worker takes the job rather than from when it is queued, or the
+    /// request is cancelled through `cancel`, the result is an `Interrupted` error. Also returns
+    /// how long each phase of the analysis took, as far as the worker got.
+    pub fn twoslash(
+        &self,
+        code: String,
+        options: SnippetOptions,
+        timeout: Option<Duration>,
+        cancel: Cancel,
+    ) -> (Result<TwoSlash>, Phases) {
+        let (reply, result) = mpsc::channel();
+        let job = Job {
+            code,
+            options,
+            cancel: cancel.clone(),
+            reply,
+        };
+        if self.jobs.as_ref().unwrap().send(job).is_err() {
+            let message = "the worker pool has shut down";
+            return (Err(anyhow::Error::msg(message)), vec![]);
+        }
+
+        // Set once a worker takes the job.
+        let mut deadline: Option<Instant> = None;
+        // When we stop waiting for a cancelled worker to come back with partial results, and leave
+        // it to finish on its own time.
+        let mut give_up = None;
+        loop {
+            match result.recv_timeout(POLL_INTERVAL) {
+                Ok(Update::Started) => {
+                    deadline = timeout.map(|timeout| Instant::now() + timeout);
+                }
+                Ok(Update::Finished(result)) => return result,
+                Err(RecvTimeoutError::Disconnected) => {
+                    let message = "the worker stopped before finishing";
+                    return (Err(anyhow::Error::msg(message)), vec![]);
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+            }
+
+            let now = Instant::now();
+            if deadline.is_some_and(|deadline| now >= deadline) {
+                cancel.cancel(Reason::Timeout);
+            }
+            if let Some(reason) = cancel.reason() {
+                if now >= *give_up.get_or_insert(now + CANCEL_GRACE) {
+                    let interrupted = Interrupted {
+                        reason: Some(reason),
+                        partial: None,
+                    };
+                    return (Err(interrupted.into()), vec![]);
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Pool {
+    fn drop(&mut self) {
+        // Hanging up on the workers tells them to stop once they finish their current job.
+        drop(self.jobs.take());
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
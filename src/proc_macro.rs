@@ -0,0 +1,46 @@
+//! The proc-macro server rust-analyzer uses to expand proc macros in cargo projects. When it
+//! loads a workspace with proc macros enabled, rust-analyzer spawns the current executable with
+//! `proc-macro` as its only argument, and expects it to speak the proc-macro server protocol over
+//! stdio.
+
+use std::path::PathBuf;
+use std::process::Command;
+
+use anyhow::Result;
+
+/// The argument rust-analyzer spawns the proc-macro server with.
+pub const SERVER_ARG: &str = "proc-macro";
+
+/// Finds the proc-macro server shipped with the local toolchain (by the `rust-analyzer`
+/// component). It is the most likely to understand proc macros built by that toolchain.
+fn sysroot_server() -> Option<PathBuf> {
+    let output = Command::new("rustc")
+        .args(["--print", "sysroot"])
+        .output()
+        .ok()?;
+    let sysroot = PathBuf::from(String::from_utf8(output.stdout).ok()?.trim());
+    let server = sysroot.join("libexec").join(format!(
+        "rust-analyzer-proc-macro-srv{}",
+        std::env::consts::EXE_SUFFIX
+    ));
+    server.exists().then_some(server)
+}
+
+/// Serves proc-macro expansion requests on stdio until rust-analyzer hangs up. Defers to the
+/// toolchain's server if there is one, and otherwise uses the one bundled with rust-analyzer.
+pub fn run_server() -> Result<()> {
+    match sysroot_server() {
+        Some(server) => {
+            let status = Command::new(server)
+                // The toolchain's server refuses to run unless it knows it is being driven by
+                // rust-analyzer.
+                .env("RUST_ANALYZER_INTERNALS_DO_NOT_USE", "this is unstable")
+                .status()?;
+            std::process::exit(status.code().unwrap_or(1));
+        }
+        None => {
+            ra_proc_macro_srv::cli::run()?;
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,88 @@
+//! Named project profiles, so that one server can analyze snippets under several configurations.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+use crate::options::{EditionOption, SnippetOptions};
+use crate::project::{ProjectKind, ProjectSettings};
+
+/// The profile requests are routed to when they don't name one.
+pub const DEFAULT_PROFILE: &str = "default";
+
+/// How a profile is described in a profiles file. Fields that are left out are taken from the
+/// server's own settings.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+struct ProfileConfig {
+    kind: Option<ProjectKind>,
+    project_name: Option<String>,
+    clippy: Option<bool>,
+    proc_macros: Option<bool>,
+    dependencies: Option<String>,
+    /// The edition to analyze snippets with when a request doesn't ask for one.
+    edition: Option<EditionOption>,
+    workers: Option<usize>,
+}
+
+pub struct Profile {
+    pub name: String,
+    pub settings: ProjectSettings,
+    pub edition: Option<EditionOption>,
+    pub workers: usize,
+}
+
+impl Profile {
+    /// Fills in the options a request left to the profile.
+    pub fn options(&self, mut options: SnippetOptions) -> SnippetOptions {
+        options.edition = options.edition.or(self.edition);
+        options
+    }
+}
+
+/// Reads profiles from a JSON file mapping profile names to their configuration, like
+/// `{"no_std": {"kind": "singleFile"}, "serde": {"kind": "cargo", "dependencies": "serde = \"1\""}}`.
+///
+/// Each profile gets its own directory under `base.dir`. The `default` profile is `base` unless the
+/// file overrides it.
+pub fn load(path: &Path, base: &ProjectSettings, workers: usize) -> Result<Vec<Profile>> {
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("could not read profiles from {}", path.display()))?;
+    let configs: BTreeMap<String, ProfileConfig> = serde_json::from_str(&contents)
+        .with_context(|| format!("invalid profiles in {}", path.display()))?;
+
+    let mut profiles = vec![];
+    if !configs.contains_key(DEFAULT_PROFILE) {
+        profiles.push(Profile {
+            name: DEFAULT_PROFILE.to_string(),
+            settings: base.clone(),
+            edition: None,
+            workers,
+        });
+    }
+    for (i, (name, config)) in configs.into_iter().enumerate() {
+        let settings = ProjectSettings {
+            kind: config.kind.unwrap_or(base.kind),
+            project_name: config
+                .project_name
+                .unwrap_or_else(|| base.project_name.clone()),
+            // Profile names come from users, so don't trust them as paths.
+            dir: base.dir.join(format!("profile-{}", i)),
+            use_clippy: config.clippy.unwrap_or(base.use_clippy),
+            with_proc_macros: config.proc_macros.unwrap_or(base.with_proc_macros),
+            dependencies: config
+                .dependencies
+                .unwrap_or_else(|| base.dependencies.clone()),
+        };
+        profiles.push(Profile {
+            name,
+            settings,
+            edition: config.edition,
+            workers: config.workers.unwrap_or(workers),
+        });
+    }
+    Ok(profiles)
+}
@@ -1,30 +1,70 @@
 use std::collections::HashMap;
 use std::fs;
-use std::path::PathBuf;
-use std::sync::Arc;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 
 use anyhow::Result;
 
 use ra::cli::load_cargo::{load_workspace, LoadCargoConfig};
 use ra_ide::{
-    Analysis, AnalysisHost, Change, CompletionConfig, Diagnostic, DiagnosticsConfig, FileId,
-    FilePosition, HoverResult, LineCol, LineIndex, StaticIndex, TextRange, TextSize, TokenId,
-    TokenStaticData,
+    Analysis, AnalysisHost, Cancelled, Change, CompletionConfig, CrateGraph, Diagnostic,
+    DiagnosticsConfig, Edition, FileId, FilePosition, FileRange, HoverConfig, HoverDocFormat,
+    HoverResult, LineCol, LineIndex, Severity, SourceRoot, TextRange, TextSize,
 };
+use ra_ide_db::base_db::SourceDatabase;
 use ra_ide_db::imports::insert_use::{ImportGranularity, InsertUseConfig, PrefixKind};
 use ra_ide_db::SnippetCap;
 use ra_project_model::{CargoConfig, ProjectManifest, ProjectWorkspace};
-use ra_vfs::{AbsPathBuf, VfsPath};
-use tempfile::TempDir;
+use ra_syntax::{AstNode, SyntaxKind, T};
+use ra_vfs::{AbsPathBuf, FileSet, VfsPath};
+use serde::{Deserialize, Serialize};
 
-use crate::query_parser::find_queries;
-use crate::twoslash::{CompletionEntry, Error, Query, QueryKind, StaticQuickInfo, TwoSlash};
+use crate::cancel::{Cancel, Interrupted};
+use crate::cargo::{self, CompilerMessage};
+use crate::directives::{find_directives, Directives};
+use crate::options::{CutMode, Encoding, SnippetOptions};
+use crate::query_parser::{find_queries, is_query};
+use crate::runner;
+use crate::rustdoc;
+use crate::stats::{Phase, Timings};
+use crate::twoslash::{
+    Clippy, CompletionEntry, DiagnosticCategory, Error, Execution, Expectation, Query, QueryKind,
+    StaticQuickInfo, TwoSlash,
+};
+use crate::wrap::{wrap_main, LineMap, Wrapped};
+
+#[derive(Copy, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ProjectKind {
+    /// Analyze the snippet on its own. Fast, but std is not available.
+    SingleFile,
+    /// Analyze the snippet alongside the local sysroot, so std is available without scaffolding a
+    /// cargo project.
+    Sysroot,
+    /// Scaffold a cargo project for the snippet.
+    Cargo,
+}
 
-#[derive(Copy, Clone)]
-pub struct ProjectSettings<'a> {
-    pub make_cargo_project: bool,
-    pub project_name: &'a str,
-    pub tmpdir: &'a TempDir,
+#[derive(Clone)]
+pub struct ProjectSettings {
+    pub kind: ProjectKind,
+    pub project_name: String,
+    /// Where to put the project's files on disk.
+    pub dir: PathBuf,
+    /// Run clippy on every snippet, not just those with a `// @clippy` directive. Only has an
+    /// effect for cargo projects.
+    pub use_clippy: bool,
+    /// Expand proc macros (like `#[derive(Serialize)]`) in cargo projects.
+    pub with_proc_macros: bool,
+    /// Lines of the `[dependencies]` section of a cargo project's manifest.
+    pub dependencies: String,
+}
+
+/// A snippet that was built, and run if it compiled.
+struct Run {
+    execution: Execution,
+    /// The codes of every error the build failed with, visible or not; see `cargo::error_codes`.
+    error_codes: Vec<String>,
 }
 
 struct Position {
@@ -36,27 +76,42 @@ struct Position {
 
 pub struct Project {
     cut: Cut,
+    source: String,
+    directives: Directives,
 
-    host: Option<AnalysisHost>,
+    /// Root of the scaffolded cargo project, if there is one.
+    cargo_root: Option<PathBuf>,
+    use_clippy: bool,
+    encoding: Encoding,
+    /// The edition the snippet is analyzed with, which cargo commands must build it with too.
+    edition: Edition,
+
+    /// Shared so that analyses can be cancelled from other threads; see `cancel::Cancel`.
+    host: Arc<Mutex<AnalysisHost>>,
     analysis: Analysis,
     queries: Vec<(QueryKind, TextSize)>,
 
     line_index: LineIndex,
-    token_to_ranges: HashMap<TokenId, Vec<TextRange>>,
-    token_data: Vec<(TokenId, TokenStaticData)>,
 
     fid: FileId,
+    /// How long analyzing the current snippet has taken so far.
+    timings: Timings,
 }
 
+/// Path of the snippet's source file, relative to the cargo project root.
+const LIB_RS: &str = "src/lib.rs";
+
 /// Bootstraps a cargo project in a directory, and returns the paths of the
 /// project root and lib.rs.
 fn bootstrap_project_in(
-    dir: &TempDir,
+    dir: &Path,
     project_name: &str,
+    dependencies: &str,
+    edition: Edition,
     source: &str,
 ) -> Result<(PathBuf, PathBuf)> {
-    let root = dir.path();
-    let lib_rs = root.join("src/lib.rs");
+    let root = dir;
+    let lib_rs = root.join(LIB_RS);
 
     // /root
     // |- Cargo.toml
@@ -68,51 +123,153 @@ fn bootstrap_project_in(
         format!(
             r#"
 [package]
-edition = "2021"
+edition = "{}"
 name = "{}"
 version = "0.0.0"
+
+[dependencies]
+{}
 "#,
-            project_name,
+            edition, project_name, dependencies,
         )
         .trim(),
     )?;
 
-    fs::create_dir(root.join("src"))?;
+    // The project may be bootstrapped again in the same directory if it has to be rebuilt.
+    fs::create_dir_all(root.join("src"))?;
 
     fs::write(lib_rs.clone(), source)?;
 
     Ok((root.to_path_buf(), lib_rs))
 }
 
-fn pre_index(
-    analysis: &Analysis,
-    fid: FileId,
-    source: &str,
+/// How hovers are rendered; the same as rust-analyzer's static index renders them.
+const HOVER_CONFIG: HoverConfig = HoverConfig {
+    links_in_hover: true,
+    documentation: Some(HoverDocFormat::Markdown),
+};
+
+/// Analyzes `code` on its own, like `Analysis::from_single_file`, but with the given edition.
+fn single_file_analysis(code: String, edition: Edition) -> (AnalysisHost, FileId) {
+    let mut host = AnalysisHost::default();
+    let fid = FileId(0);
+    let mut file_set = FileSet::default();
+    file_set.insert(fid, VfsPath::new_virtual_path("/main.rs".to_string()));
+
+    let mut changes = Change::new();
+    changes.set_roots(vec![SourceRoot::new_local(file_set)]);
+    let mut crate_graph = CrateGraph::default();
+    crate_graph.add_crate_root(
+        fid,
+        edition,
+        None,
+        None,
+        Default::default(),
+        Default::default(),
+        Default::default(),
+        Default::default(),
+        Default::default(),
+    );
+    changes.change_file(fid, Some(Arc::new(code)));
+    changes.set_crate_graph(crate_graph);
+    host.apply_change(changes);
+
+    (host, fid)
+}
+
+/// Sets the edition of the crate rooted at `fid`, if it does not already have that edition.
+fn set_edition(host: &mut AnalysisHost, fid: FileId, edition: Edition) {
+    let graph = host.raw_database().crate_graph();
+    let up_to_date = graph
+        .iter()
+        .filter(|&id| graph[id].root_file_id == fid)
+        .all(|id| graph[id].edition == edition);
+    if up_to_date {
+        return;
+    }
+
+    // Crates can't be edited in place, so rebuild the whole graph with the new edition.
+    let mut new_graph = CrateGraph::default();
+    let mut new_ids = HashMap::new();
+    for id in graph.iter() {
+        let data = &graph[id];
+        let new_id = new_graph.add_crate_root(
+            data.root_file_id,
+            match data.root_file_id == fid {
+                true => edition,
+                false => data.edition,
+            },
+            data.display_name.clone(),
+            data.version.clone(),
+            data.cfg_options.clone(),
+            data.potential_cfg_options.clone(),
+            data.env.clone(),
+            data.proc_macro.clone(),
+            data.origin.clone(),
+        );
+        new_ids.insert(id, new_id);
+    }
+    for id in graph.iter() {
+        for dep in graph[id].dependencies.iter() {
+            // The old graph was acyclic, so the new one is too.
+            let _ = new_graph.add_dep(new_ids[&id], dep.name.clone(), new_ids[&dep.crate_id]);
+        }
+    }
+
+    let mut changes = Change::new();
+    changes.set_crate_graph(new_graph);
+    host.apply_change(changes);
+}
+
+/// Strips the twoslash markup from a snippet and wraps it in `fn main` if it needs to be. Returns
+/// the code to analyze, along with everything needed to map results back to the snippet.
+fn prepare(
+    snippet: &str,
+    options: &SnippetOptions,
 ) -> (
-    HashMap<TokenId, Vec<TextRange>>,
-    Vec<(TokenId, TokenStaticData)>,
+    String,
+    Directives,
+    Vec<(QueryKind, TextSize)>,
     LineIndex,
     Cut,
 ) {
-    let si = StaticIndex::compute(&analysis);
-
-    let mut token_to_ranges = HashMap::<TokenId, Vec<TextRange>>::default();
-    for (range, id) in si
-        .files
-        .iter()
-        .find(|fi| fi.file_id == fid)
-        .unwrap()
-        .tokens
-        .iter()
-    {
-        token_to_ranges.entry(*id).or_default().push(*range);
-    }
-    let token_data = si.tokens.iter().collect();
+    let (snippet, mut directives) = find_directives(snippet, options);
+    let query_lines: Vec<u32> = (0..)
+        .zip(snippet.lines())
+        .filter(|(_, line)| is_query(line))
+        .map(|(i, _)| i)
+        .collect();
+    let (snippet, queries) = find_queries(&snippet);
 
-    let line_index = LineIndex::new(source);
-    let cut = Cut::new(source, &line_index);
+    let (code, cut) = match wrap_main(&snippet) {
+        Some(Wrapped { code, lines }) => (code, Cut::new(&snippet, Some(lines), options.cut)),
+        None => {
+            let cut = Cut::new(&snippet, None, options.cut);
+            (snippet, cut)
+        }
+    };
+    let line_index = LineIndex::new(&code);
+    // Queries that can't be found in the analyzed code can't be answered, so leave them out.
+    let queries = queries
+        .into_iter()
+        .filter_map(|(kind, pos)| Some((kind, cut.to_analyzed(pos, &line_index)?)))
+        .collect();
+    // Tags were placed before queries were removed, and count lines from the top of the snippet
+    // rather than of the cut. Tags outside the cut go with the rest of the hidden code.
+    directives.tags = std::mem::take(&mut directives.tags)
+        .into_iter()
+        .filter_map(|mut tag| {
+            let queries_above = query_lines.iter().filter(|&&line| line < tag.line).count();
+            tag.line -= queries_above as u32;
+            if tag.line < cut.start_line || tag.line > cut.end_line {
+                return None;
+            }
+            tag.line -= cut.start_line;
+            Some(tag)
+        })
+        .collect();
 
-    (token_to_ranges, token_data, line_index, cut)
+    (code, directives, queries, line_index, cut)
 }
 
 impl Project {
@@ -121,95 +278,148 @@ impl Project {
             settings,
             // Basis code for scaffolding
             r#"pub fn foo() -> usize { 1 }"#,
+            &SnippetOptions::default(),
         )
     }
 
     /// Let `scaffold`, but injects user code immediately.
-    pub fn scaffold_with_code<'a>(settings: ProjectSettings, source: &'a str) -> Result<Project> {
-        let (source, queries) = find_queries(source);
-
-        let (host, analysis, fid) = if !settings.make_cargo_project {
-            let (analysis, fid) = Analysis::from_single_file(source.to_string());
-            (None, analysis, fid)
-        } else {
-            let (root, lib_rs) =
-                bootstrap_project_in(settings.tmpdir, settings.project_name, &source)?;
-
-            let cargo_config = CargoConfig::default();
-            let no_progress = &|_| ();
-            let load_cargo_config = LoadCargoConfig {
-                load_out_dirs_from_check: true,
-                with_proc_macro: false,
-                prefill_caches: false,
-            };
-            let path = AbsPathBuf::assert(root);
-            let manifest = ProjectManifest::discover_single(&path)?;
+    pub fn scaffold_with_code<'a>(
+        settings: ProjectSettings,
+        source: &'a str,
+        options: &SnippetOptions,
+    ) -> Result<Project> {
+        let timings = Timings::default();
+        let (source, directives, queries, line_index, cut) =
+            timings.time(Phase::FindQueries, || prepare(source, options));
+        let edition = options.edition.map_or(Edition::CURRENT, Edition::from);
 
-            let workspace = ProjectWorkspace::load(manifest, &cargo_config, no_progress)?;
+        let (host, analysis, fid, cargo_root) = match settings.kind {
+            ProjectKind::SingleFile => {
+                let (host, fid) = single_file_analysis(source.to_string(), edition);
+                let analysis = host.analysis();
+                (host, analysis, fid, None)
+            }
+            ProjectKind::Sysroot => {
+                // The snippet has to be written to disk: rust-analyzer finds the sysroot for a
+                // detached file by running rustc in the file's directory (so toolchain overrides
+                // apply), and `load_workspace` reads the file's contents from its real path. It
+                // is only read while loading; later changes, like session edits, only go through
+                // the VFS, so the copy on disk may go stale and is never read again.
+                let main_rs = settings.dir.join("main.rs");
+                fs::write(&main_rs, &source)?;
+
+                let load_config = LoadCargoConfig {
+                    load_out_dirs_from_check: false,
+                    with_proc_macro: false,
+                    prefill_caches: false,
+                };
+                let workspace = ProjectWorkspace::load_detached_files(vec![AbsPathBuf::assert(
+                    main_rs.clone(),
+                )])?;
 
-            let (host, vfs, _proc_macro) = load_workspace(workspace, &load_cargo_config)?;
+                let (mut host, vfs, _proc_macro) = load_workspace(workspace, &load_config)?;
 
-            let analysis = host.analysis();
+                let fid = vfs
+                    .file_id(&VfsPath::new_real_path(main_rs.display().to_string()))
+                    .unwrap();
+                set_edition(&mut host, fid, edition);
+                let analysis = host.analysis();
 
-            let _si = StaticIndex::compute(&analysis);
+                (host, analysis, fid, None)
+            }
+            ProjectKind::Cargo => {
+                let (root, lib_rs) = bootstrap_project_in(
+                    &settings.dir,
+                    &settings.project_name,
+                    &settings.dependencies,
+                    edition,
+                    &source,
+                )?;
 
-            let fid = vfs
-                .file_id(&VfsPath::new_real_path(lib_rs.display().to_string()))
-                .unwrap();
-            let analysis = host.analysis();
+                let cargo_config = CargoConfig::default();
+                let no_progress = &|_| ();
+                let load_cargo_config = LoadCargoConfig {
+                    load_out_dirs_from_check: true,
+                    with_proc_macro: settings.with_proc_macros,
+                    prefill_caches: false,
+                };
+                let path = AbsPathBuf::assert(root.clone());
+                let manifest = ProjectManifest::discover_single(&path)?;
 
-            (Some(host), analysis, fid)
-        };
+                let workspace = ProjectWorkspace::load(manifest, &cargo_config, no_progress)?;
 
-        let (token_to_ranges, token_data, line_index, cut) = pre_index(&analysis, fid, &source);
+                let (mut host, vfs, _proc_macro) = load_workspace(workspace, &load_cargo_config)?;
+
+                let fid = vfs
+                    .file_id(&VfsPath::new_real_path(lib_rs.display().to_string()))
+                    .unwrap();
+                set_edition(&mut host, fid, edition);
+                let analysis = host.analysis();
+
+                (host, analysis, fid, Some(root))
+            }
+        };
 
         Ok(Project {
             cut,
+            source,
+            directives,
 
-            host,
+            cargo_root,
+            use_clippy: settings.use_clippy,
+            encoding: options.encoding,
+            edition,
+
+            host: Arc::new(Mutex::new(host)),
             analysis,
             queries,
 
             line_index,
-            token_to_ranges,
-            token_data,
 
             fid,
+            timings,
         })
     }
 
-    pub fn apply_change(self, new_code: String) -> Self {
+    pub fn timings(&self) -> &Timings {
+        &self.timings
+    }
+
+    /// The host the project's analyses come from, for cancelling them.
+    pub fn host(&self) -> &Arc<Mutex<AnalysisHost>> {
+        &self.host
+    }
+
+    pub fn apply_change(self, new_code: String, options: &SnippetOptions) -> Self {
         // The analysis is now stale. Drop it so that we don't block host update below.
         drop(self.analysis);
 
-        let (new_code, queries) = find_queries(&new_code);
-
-        let (host, analysis, fid) = match self.host {
-            Some(mut host) => {
-                let mut changes = Change::new();
-                changes.change_file(self.fid, Some(Arc::new(new_code.clone())));
-                host.apply_change(changes);
-                let analysis = host.analysis();
-                (Some(host), analysis, self.fid)
-            }
-            None => {
-                // This is a standalone rust script.
-                let (analysis, fid) = Analysis::from_single_file(new_code.clone());
-                (None, analysis, fid)
-            }
-        };
+        let timings = Timings::default();
+        let (new_code, directives, queries, line_index, cut) =
+            timings.time(Phase::FindQueries, || prepare(&new_code, options));
+        let edition = options.edition.map_or(Edition::CURRENT, Edition::from);
 
-        let (token_to_ranges, token_data, line_index, cut) = pre_index(&analysis, fid, &new_code);
+        // Whatever the kind of project, the snippet is a single file in the host's database, so
+        // changing just that file lets rust-analyzer reuse everything the new code doesn't touch.
+        let analysis = timings.time(Phase::ApplyChange, || {
+            let mut host = self.host.lock().unwrap();
+            let mut changes = Change::new();
+            changes.change_file(self.fid, Some(Arc::new(new_code.clone())));
+            host.apply_change(changes);
+            set_edition(&mut host, self.fid, edition);
+            host.analysis()
+        });
 
         Self {
-            host,
             analysis,
             queries,
-            fid,
-            token_to_ranges,
-            token_data,
             line_index,
             cut,
+            source: new_code,
+            directives,
+            encoding: options.encoding,
+            edition,
+            timings,
             ..self
         }
     }
@@ -222,13 +432,20 @@ impl Project {
             line,
             col: character,
         } = self.line_index.line_col(start);
+        let line = self.cut.to_basis_line(line)?;
         match self.cut.line_in_cut(line) {
-            true => Some(Position {
-                start: u32::from(start) - self.cut.start_offset,
-                length: (end - start).into(),
-                line: line - self.cut.start_line,
-                character,
-            }),
+            true => {
+                let start_in_basis = self.cut.line_index.offset(LineCol {
+                    line,
+                    col: character,
+                })?;
+                Some(Position {
+                    start: u32::from(start_in_basis) - self.cut.start_offset,
+                    length: (end - start).into(),
+                    line: line - self.cut.start_line,
+                    character,
+                })
+            }
             false => None,
         }
     }
@@ -274,72 +491,304 @@ impl Project {
         Ok(diags)
     }
 
-    fn ident_hovers(&self) -> Result<Vec<StaticQuickInfo>> {
-        let hovers = self
-            .token_data
-            .iter()
-            .filter_map(|(id, token)| token.hover.as_ref().map(|hover| (id, hover)))
-            .flat_map(|(id, hover): (&TokenId, &HoverResult)| {
-                self.token_to_ranges
-                    .get(&id)
-                    .map(|ranges| {
-                        // Annoying, but we have to do this here. We can't unwrap_or_default first
-                        // because then we take a reference to a Vec, and rustc thinks we return
-                        // meaningful data inside that temporary.
-                        ranges
-                            .iter()
-                            .map(|range| (range, hover))
-                            .collect::<Vec<_>>()
-                    })
-                    .unwrap_or_default()
+    /// Writes the snippet, and the edition it is analyzed with, to the cargo project at `root`.
+    /// Changes are only applied to the VFS otherwise, but cargo reads the project from disk.
+    fn write_to_disk(&self, root: &Path) -> Result<()> {
+        fs::write(root.join(LIB_RS), &self.source)?;
+
+        let cargo_toml = root.join("Cargo.toml");
+        let manifest = fs::read_to_string(&cargo_toml)?;
+        let edition = format!("edition = \"{}\"", self.edition);
+        // The manifest was bootstrapped with the edition as the first line of `[package]`.
+        let old_edition = manifest.lines().find(|line| line.starts_with("edition = "));
+        if let Some(old_edition) = old_edition.filter(|&line| line != edition) {
+            fs::write(&cargo_toml, manifest.replacen(old_edition, &edition, 1))?;
+        }
+        Ok(())
+    }
+
+    /// Lints from `cargo clippy`, as warnings whatever their level, if clippy was requested for
+    /// this snippet and this is a cargo project. Also says whether clippy ran, if the snippet
+    /// asked for it.
+    fn clippy_lints(&self, cancel: &Cancel) -> Result<(Vec<Error>, Option<Clippy>)> {
+        let lints = self.directives.clippy.clone().unwrap_or_default();
+        let root = match &self.cargo_root {
+            Some(root) if self.use_clippy || self.directives.clippy.is_some() => root,
+            // Only cargo projects can be linted, which a snippet asking for clippy should hear.
+            None if self.directives.clippy.is_some() => {
+                let clippy = Clippy {
+                    lints,
+                    ran: false,
+                    reason: Some("clippy can only run in cargo mode".to_string()),
+                };
+                return Ok((vec![], Some(clippy)));
+            }
+            _ => return Ok((vec![], None)),
+        };
+
+        self.write_to_disk(root)?;
+
+        let errors = cargo::clippy(root, &lints, cancel)?
+            .into_iter()
+            .filter_map(|msg| self.compiler_message_to_error(msg, LIB_RS))
+            // Deny-by-default lints are still lints, not errors in the snippet.
+            .map(|error| Error {
+                category: DiagnosticCategory::Warning,
+                ..error
             })
-            .filter_map(|(range, hover)| {
-                self.to_position(*range).map(
-                    |Position {
-                         start,
-                         length,
-                         line,
-                         character,
-                     }| {
-                        let target_string = self.cut.source
-                            [(start as usize)..((start + length) as usize)]
-                            .to_string();
+            .collect();
+        let clippy = Clippy {
+            lints,
+            ran: true,
+            reason: None,
+        };
+        Ok((errors, Some(clippy)))
+    }
 
-                        let markup = hover.markup.to_string();
-                        let text = ra_hover_to_text(markup);
+    /// Converts a rustc message about `file_name` to an error on the snippet, or `None` if the
+    /// message is not about the visible part of the snippet.
+    fn compiler_message_to_error(&self, msg: CompilerMessage, file_name: &str) -> Option<Error> {
+        let span = msg.primary_span_in(file_name)?;
+        let range = TextRange::new(span.byte_start.into(), span.byte_end.into());
+        let Position {
+            start,
+            length,
+            line,
+            character,
+        } = self.to_position(range)?;
+        Some(Error {
+            rendered_message: msg.render(),
+            id: msg.code.map(|code| code.code).unwrap_or_default(),
+            category: DiagnosticCategory::from_rustc_level(&msg.level),
+            code: 0,
+            start,
+            length,
+            line,
+            character,
+        })
+    }
 
-                        StaticQuickInfo {
-                            target_string,
-                            text,
-                            docs: None,
-                            start,
-                            length,
-                            line,
-                            character,
-                        }
-                    },
-                )
+    /// Whether the snippet should be run, if it can be.
+    fn wants_run(&self) -> bool {
+        match &self.directives.rustdoc {
+            // Like rustdoc, a snippet that should panic is run to check that it does.
+            Some(attributes) => {
+                attributes.allows_run() && (self.directives.run || attributes.should_panic)
+            }
+            None => self.directives.run,
+        }
+    }
+
+    /// Builds and runs the snippet, if it asked to be run and this is a cargo project.
+    fn execution(&self, cancel: &Cancel) -> Result<Option<Run>> {
+        let root = match &self.cargo_root {
+            Some(root) if self.wants_run() => root,
+            _ => return Ok(None),
+        };
+
+        // The library target is built as a dependency of the binary, so keep it in sync too.
+        self.write_to_disk(root)?;
+        let cargo::Build {
+            executable,
+            messages,
+        } = cargo::build_bin(root, &self.source, cancel)?;
+
+        let error_codes = cargo::error_codes(&messages);
+        // The library and binary have the same source, and the binary is only compiled if the
+        // library compiles, so errors show up in exactly one of the two.
+        let errors = messages
+            .into_iter()
+            .filter(|msg| msg.level == "error")
+            .filter_map(|msg| {
+                let file_name = match msg.primary_span_in(cargo::MAIN_RS) {
+                    Some(_) => cargo::MAIN_RS,
+                    None => LIB_RS,
+                };
+                self.compiler_message_to_error(msg, file_name)
             })
             .collect();
-        Ok(hovers)
+        let executable = match executable {
+            Some(executable) => executable,
+            None => {
+                let execution = Execution {
+                    compiled: false,
+                    reason: None,
+                    errors,
+                    stdout: String::new(),
+                    stderr: String::new(),
+                    exit_code: None,
+                    panic: None,
+                    timed_out: false,
+                    truncated: false,
+                };
+                return Ok(Some(Run {
+                    execution,
+                    error_codes,
+                }));
+            }
+        };
+
+        let output = runner::run(
+            &executable,
+            runner::DEFAULT_TIMEOUT,
+            runner::DEFAULT_MAX_OUTPUT,
+            cancel,
+        )?;
+        let execution = Execution {
+            compiled: true,
+            reason: None,
+            errors,
+            panic: output.panic_message(),
+            stdout: output.stdout,
+            stderr: output.stderr,
+            exit_code: output.exit_code,
+            timed_out: output.timed_out,
+            truncated: output.truncated,
+        };
+        Ok(Some(Run {
+            execution,
+            error_codes,
+        }))
     }
 
-    fn find_hover_data_at_position(&self, pos: TextSize) -> Option<(TextRange, &HoverResult)> {
-        let hover_from_static_index = self.token_data.iter().find_map(|(id, data)| {
-            let range = self
-                .token_to_ranges
-                .get(id)
-                .and_then(|ranges| ranges.iter().find(|range| range.contains(pos)));
-            match (range, data.hover.as_ref()) {
-                (Some(range), Some(data)) => Some((*range, data)),
-                _ => None,
+    /// Checks the snippet against its rustdoc attributes, if it has any. Only compile errors count
+    /// against them, never lints, even clippy's deny-by-default ones.
+    fn expectation(&self, run: Option<&Run>, cancel: &Cancel) -> Result<Option<Expectation>> {
+        let attributes = match &self.directives.rustdoc {
+            Some(attributes) => attributes,
+            None => return Ok(None),
+        };
+
+        // Error codes from rustc are the most accurate, so in cargo mode they are always used.
+        // They include errors in hidden code, which fail a doctest all the same.
+        let compile_errors: Vec<String> = match (&self.cargo_root, run) {
+            (_, Some(run)) => run.error_codes.clone(),
+            (Some(root), None) => {
+                self.write_to_disk(root)?;
+                cargo::error_codes(&cargo::check(root, cancel)?)
             }
-        });
-        hover_from_static_index
+            // rust-analyzer's diagnostics are not identified by rustc's error codes.
+            (None, None) if attributes.compile_fail && !attributes.error_codes.is_empty() => {
+                return Ok(Some(Expectation {
+                    attributes: attributes.source.clone(),
+                    held: None,
+                    reason: Some("error codes can only be checked in cargo mode".to_string()),
+                }));
+            }
+            // Unlike the snippet's errors, these aren't cut down to its visible part.
+            (None, None) => self
+                .analysis
+                .diagnostics(
+                    &DiagnosticsConfig::default(),
+                    ra_ide::AssistResolveStrategy::None,
+                    self.fid,
+                )?
+                .into_iter()
+                .filter(|diag| matches!(diag.severity, Severity::Error))
+                .map(|diag| diag.code.as_str().to_string())
+                .collect(),
+        };
+
+        // None of these sources lint today, but a lint must never satisfy `compile_fail`.
+        let compile_errors: Vec<String> = compile_errors
+            .into_iter()
+            .filter(|code| !code.starts_with("clippy::"))
+            .collect();
+
+        let execution = run.map(|run| &run.execution);
+        Ok(Some(rustdoc::check(attributes, &compile_errors, execution)))
+    }
+
+    /// Hovers over every identifier in the cut, and every integer that names a tuple field (as in
+    /// `pair.0`), that resolves to a definition. Like rust-analyzer's `StaticIndex`, this leaves
+    /// out literals and unresolved names, which hover would otherwise describe by their type.
+    /// Tokens outside the cut, like those of a hidden prelude, are never hovered. If the analysis
+    /// is cancelled, the hovers so far are returned along with `Cancelled`.
+    fn ident_hovers(&self) -> (Vec<StaticQuickInfo>, Option<Cancelled>) {
+        let root = match self.analysis.parse(self.fid) {
+            Ok(root) => root,
+            Err(cancelled) => return (vec![], Some(cancelled)),
+        };
+        let tokens = root
+            .syntax()
+            .descendants_with_tokens()
+            .filter_map(|element| element.into_token())
+            .filter(|token| match token.kind() {
+                SyntaxKind::IDENT
+                | SyntaxKind::LIFETIME_IDENT
+                | T![self]
+                | T![super]
+                | T![crate] => true,
+                SyntaxKind::INT_NUMBER => token
+                    .parent()
+                    .is_some_and(|parent| parent.kind() == SyntaxKind::NAME_REF),
+                _ => false,
+            });
+
+        let mut hovers = vec![];
+        for token in tokens {
+            let range = token.text_range();
+            let position = match self.to_position(range) {
+                Some(position) => position,
+                None => continue,
+            };
+            match self.defines(range.start()) {
+                Ok(true) => {}
+                Ok(false) => continue,
+                Err(cancelled) => return (hovers, Some(cancelled)),
+            }
+            let hover = match self.hover_at(range.start()) {
+                Ok(Some((_, hover))) => hover,
+                Ok(None) => continue,
+                Err(cancelled) => return (hovers, Some(cancelled)),
+            };
+            let Position {
+                start,
+                length,
+                line,
+                character,
+            } = position;
+            let target_string =
+                self.cut.source[(start as usize)..((start + length) as usize)].to_string();
+
+            let markup = hover.markup.to_string();
+            let text = ra_hover_to_text(markup);
+
+            hovers.push(StaticQuickInfo {
+                target_string,
+                text,
+                docs: None,
+                start,
+                length,
+                line,
+                character,
+            });
+        }
+        (hovers, None)
+    }
+
+    /// Whether the token at `pos` resolves to a definition, or is one.
+    fn defines(&self, pos: TextSize) -> Result<bool, Cancelled> {
+        let position = FilePosition {
+            file_id: self.fid,
+            offset: pos,
+        };
+        let targets = self.analysis.goto_definition(position)?;
+        Ok(targets.is_some_and(|targets| !targets.info.is_empty()))
+    }
+
+    /// Hovers over the token at `pos`, returning its range along with the hover.
+    fn hover_at(&self, pos: TextSize) -> Result<Option<(TextRange, HoverResult)>, Cancelled> {
+        let range = FileRange {
+            file_id: self.fid,
+            range: TextRange::empty(pos),
+        };
+        let hover = self.analysis.hover(&HOVER_CONFIG, range)?;
+        Ok(hover.map(|info| (info.range, info.info)))
     }
 
     fn query(&self, pos: TextSize) -> Result<Query> {
-        let (range, info) = match self.find_hover_data_at_position(pos) {
+        let (range, info) = match self.hover_at(pos)? {
             None => return Err(anyhow::Error::msg("")),
             Some(info) => info,
         };
@@ -432,23 +881,80 @@ impl Project {
         })
     }
 
-    fn queries(&self) -> Vec<Query> {
-        self.queries
-            .iter()
-            .filter_map(|(kind, pos)| {
-                match kind {
-                    QueryKind::Query => self.query(*pos),
-                    QueryKind::Completions => self.completions(*pos),
+    /// Answers the snippet's queries. Queries that can't be answered are left out; if the analysis
+    /// is cancelled, the ones answered so far are returned along with `Cancelled`.
+    fn queries(&self) -> (Vec<Query>, Option<Cancelled>) {
+        let mut queries = vec![];
+        for (kind, pos) in &self.queries {
+            let query = match kind {
+                QueryKind::Query => self.query(*pos),
+                QueryKind::Completions => self.completions(*pos),
+            };
+            match query {
+                Ok(query) => queries.push(query),
+                Err(e) => {
+                    if let Ok(cancelled) = e.downcast::<Cancelled>() {
+                        return (queries, Some(cancelled));
+                    }
                 }
-                .ok()
-            })
-            .collect()
+            }
+        }
+        (queries, None)
     }
 
-    pub fn twoslasher(&self) -> Result<TwoSlash> {
-        let errors = self.diagnostics()?;
-        let static_quick_infos = self.ident_hovers()?;
-        let queries = self.queries();
+    /// Computes the twoslash result. If the analysis is cancelled part way through, the result so
+    /// far is returned as an `Interrupted` error. Cargo commands are killed once `cancel` is
+    /// cancelled, which leaves no result to return.
+    pub fn twoslasher(&self, cancel: &Cancel) -> Result<TwoSlash> {
+        // Snippets marked `ignore` are not checked at all.
+        let check = !matches!(&self.directives.rustdoc, Some(attributes) if attributes.ignore);
+        let timings = &self.timings;
+        let (static_quick_infos, mut cancelled) =
+            timings.time(Phase::Hovers, || self.ident_hovers());
+        let mut errors = vec![];
+        if check && cancelled.is_none() {
+            match timings.time(Phase::Diagnostics, || self.diagnostics()) {
+                Ok(diagnostics) => errors.extend(diagnostics),
+                Err(e) => cancelled = Some(e.downcast::<Cancelled>()?),
+            }
+        }
+        let mut queries = vec![];
+        if cancelled.is_none() {
+            (queries, cancelled) = timings.time(Phase::Queries, || self.queries());
+        }
+        // Linting, running and checking expectations are all-or-nothing, and not worth starting
+        // once the analysis has been cancelled.
+        let (clippy, execution, expectation) = match cancelled {
+            None => {
+                let mut clippy = None;
+                if check {
+                    let (lints, report) =
+                        timings.time(Phase::Clippy, || self.clippy_lints(cancel))?;
+                    errors.extend(lints);
+                    clippy = report;
+                }
+                let run = timings.time(Phase::Execution, || self.execution(cancel))?;
+                let expectation = self.expectation(run.as_ref(), cancel)?;
+                let execution = match run {
+                    Some(run) => Some(run.execution),
+                    // Only cargo projects can be built, which a snippet asking to run should hear.
+                    None if self.wants_run() && self.cargo_root.is_none() => Some(Execution {
+                        compiled: false,
+                        reason: Some("snippets can only be run in cargo mode".to_string()),
+                        errors: vec![],
+                        stdout: String::new(),
+                        stderr: String::new(),
+                        exit_code: None,
+                        panic: None,
+                        timed_out: false,
+                        truncated: false,
+                    }),
+                    None => None,
+                };
+                (clippy, execution, expectation)
+            }
+            Some(_) => (None, None, None),
+        };
 
         let two_slash_result = TwoSlash {
             code: self.cut.source.to_string(),
@@ -456,13 +962,26 @@ impl Project {
             highlights: vec![],
             static_quick_infos,
             queries,
-            // TODO: real tags
-            tags: vec![],
+            tags: self.directives.tags.clone(),
             errors,
             // TODO: real URL
             playground_url: "https://play.rust-lang.org".to_string(),
+            clippy,
+            execution,
+            expectation,
         };
-        Ok(two_slash_result)
+        let two_slash_result = match self.encoding {
+            Encoding::Utf8 => two_slash_result,
+            Encoding::Utf16 => two_slash_result.with_utf16_offsets(),
+        };
+        match cancelled {
+            None => Ok(two_slash_result),
+            Some(_) => Err(Interrupted {
+                reason: None,
+                partial: Some(two_slash_result),
+            }
+            .into()),
+        }
     }
 }
 
@@ -471,15 +990,23 @@ struct Cut {
     start_line: u32,
     start_offset: u32,
     end_line: u32,
+    /// Line index of the code the cut was made from.
+    line_index: LineIndex,
+    /// If the code was wrapped in `fn main` for analysis, where its lines ended up.
+    wrapped: Option<LineMap>,
 }
 
 impl Cut {
-    fn new(basis: &str, line_index: &LineIndex) -> Cut {
+    fn new(basis: &str, wrapped: Option<LineMap>, mode: CutMode) -> Cut {
         static CUT_BEFORE_STR: &'static str = "// ---cut---\n";
         static CUT_AFTER_STR: &'static str = "// ---cut-after---\n";
 
-        let (start_line, start_offset) = basis
-            .find(CUT_BEFORE_STR)
+        let line_index = LineIndex::new(basis);
+        let find_marker = |marker: &str| match mode {
+            CutMode::Markers => basis.find(marker),
+            CutMode::None => None,
+        };
+        let (start_line, start_offset) = find_marker(CUT_BEFORE_STR)
             .map(|offset| {
                 let LineCol { line, .. } = line_index.line_col(TextSize::from(offset as u32));
                 let start_line = line + 1;
@@ -487,8 +1014,7 @@ impl Cut {
                 (start_line, start_offset)
             })
             .unwrap_or((0, 0));
-        let (end_line, end_offset) = basis
-            .find(CUT_AFTER_STR)
+        let (end_line, end_offset) = find_marker(CUT_AFTER_STR)
             .map(|offset| {
                 let end_line = line_index.line_col(TextSize::from(offset as u32)).line;
                 let end_offset = offset as u32; // We'll pick out the trailing newline elsewhere
@@ -505,12 +1031,37 @@ impl Cut {
             start_line,
             start_offset,
             end_line,
+            line_index,
+            wrapped,
         }
     }
 
     fn line_in_cut(&self, line: u32) -> bool {
         line >= self.start_line && line < self.end_line
     }
+
+    /// Maps a line of the analyzed code to the line of the basis it came from, or `None` if the
+    /// line was added by wrapping.
+    fn to_basis_line(&self, line: u32) -> Option<u32> {
+        match &self.wrapped {
+            Some(lines) => lines.to_original(line),
+            None => Some(line),
+        }
+    }
+
+    /// Maps an offset in the basis to the corresponding offset in the analyzed code, if it has
+    /// one.
+    fn to_analyzed(&self, offset: TextSize, analyzed: &LineIndex) -> Option<TextSize> {
+        let lines = match &self.wrapped {
+            Some(lines) => lines,
+            None => return Some(offset),
+        };
+        let LineCol { line, col } = self.line_index.line_col(offset);
+        analyzed.offset(LineCol {
+            line: lines.to_wrapped(line)?,
+            col,
+        })
+    }
 }
 
 fn ra_hover_to_text(markup: String) -> String {
@@ -521,3 +1072,74 @@ fn ra_hover_to_text(markup: String) -> String {
         .collect::<Vec<_>>()
         .join("\n")
 }
+
+#[cfg(test)]
+mod test {
+    use tempfile::TempDir;
+
+    use crate::cancel::Cancel;
+    use crate::options::SnippetOptions;
+
+    use super::{prepare, Project, ProjectKind, ProjectSettings};
+
+    #[test]
+    fn test_tags_follow_the_cut() {
+        let options = SnippetOptions {
+            custom_tags: vec!["annotate".to_string()],
+            ..Default::default()
+        };
+        let src = r#"
+fn hidden() {}
+// @annotate: hidden
+fn also_hidden() {}
+// ---cut---
+let x = 1;
+//  ^?
+// @annotate: shown
+let y = 2;
+"#
+        .trim();
+        let (_, directives, _, _, cut) = prepare(src, &options);
+
+        assert_eq!(cut.source, "let x = 1;\nlet y = 2;");
+        assert_eq!(directives.tags.len(), 1);
+        assert_eq!(directives.tags[0].annotation.as_deref(), Some("shown"));
+        assert_eq!(directives.tags[0].line, 1);
+    }
+
+    #[test]
+    fn test_hover_only_definitions() {
+        let dir = TempDir::new().unwrap();
+        let settings = ProjectSettings {
+            kind: ProjectKind::SingleFile,
+            project_name: "twoslash-rust-project".to_string(),
+            dir: dir.path().to_path_buf(),
+            use_clippy: false,
+            with_proc_macros: false,
+            dependencies: String::new(),
+        };
+        let code = r#"
+struct Pair(i32, i32);
+fn main() {
+    let x = 1;
+    let pair = Pair(x, 2);
+    let y = pair.0;
+}
+"#
+        .trim();
+        let project =
+            Project::scaffold_with_code(settings, code, &SnippetOptions::default()).unwrap();
+        let result = project.twoslasher(&Cancel::default()).unwrap();
+
+        let hovered: Vec<_> = result
+            .static_quick_infos
+            .iter()
+            .map(|info| info.target_string.as_str())
+            .collect();
+        assert!(!hovered.contains(&"1"));
+        assert!(!hovered.contains(&"2"));
+        assert!(hovered.contains(&"0"));
+        assert!(hovered.contains(&"x"));
+        assert!(hovered.contains(&"Pair"));
+    }
+}
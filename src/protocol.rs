@@ -1,27 +1,432 @@
-use std::io::{Read, Write};
+//! How clients and servers frame messages, and the JSON envelopes they send in them.
+//!
+//! A frame is a header, then a payload of UTF-8 JSON (or, for legacy clients, code). The header is
+//! the magic bytes `TWS`, a framing version (`FRAME_VERSION`), and the length of the payload as a
+//! 4-byte big-endian integer. Legacy clients send the length alone. The first byte tells the two
+//! apart: a legacy frame starting with `T` would be larger than any frame we accept. Responses
+//! are framed the way their requests were.
+
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::string::FromUtf8Error;
 
 use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::options::SnippetOptions;
 
-pub fn read(mut reader: impl Read) -> Result<String> {
-    let mut msg_size_buf = [0; 4];
-    reader.read_exact(&mut msg_size_buf)?;
-    let msg_size = u32::from_be_bytes(msg_size_buf);
+/// The bytes tagged frames start with.
+pub const MAGIC: &[u8; 3] = b"TWS";
+/// The version of the frame header that follows `MAGIC`.
+pub const FRAME_VERSION: u8 = 1;
+/// The largest payload we read or write. Larger frames are rejected before they are read.
+pub const MAX_FRAME: usize = 16 * 1024 * 1024;
 
-    let mut msg_buf = vec![0; msg_size as usize];
-    reader.read_exact(&mut msg_buf)?;
+/// How a frame's header is laid out.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Framing {
+    /// `MAGIC`, `FRAME_VERSION`, then the length.
+    Tagged,
+    /// Just the length, as sent by legacy clients.
+    Bare,
+}
 
-    // protocol demands that the message be valid UTF-8
-    let result = String::from_utf8(msg_buf)?;
-    Ok(result)
+/// Why a frame could not be read or written.
+#[derive(Debug)]
+pub enum FrameError {
+    /// The payload is larger than `MAX_FRAME`. It was not read.
+    TooLarge(u64),
+    /// The header has a framing version we don't speak, so we can't tell where the frame ends.
+    UnsupportedVersion(u8),
+    /// The payload is not UTF-8. It was read, so the next frame can be.
+    NotUtf8(FromUtf8Error),
 }
 
-pub fn write(mut writer: impl Write, msg: &str) -> Result<()> {
-    let msg_size = msg.len();
-    let msg_size_buf = u32::to_be_bytes(msg_size as u32);
-    let mut msg_buf = Vec::new();
-    msg_buf.extend_from_slice(&msg_size_buf);
-    msg_buf.extend_from_slice(msg.as_bytes());
+impl FrameError {
+    /// A stable, machine-readable name for the error; see `ErrorResponse`.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            FrameError::TooLarge(_) => "frameTooLarge",
+            FrameError::UnsupportedVersion(_) => "unsupportedFraming",
+            FrameError::NotUtf8(_) => "badFrame",
+        }
+    }
+
+    /// Whether the connection is still in step, so that more frames can be read from it.
+    pub fn resumable(&self) -> bool {
+        matches!(self, FrameError::NotUtf8(_))
+    }
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::TooLarge(size) => write!(
+                f,
+                "frame of {} bytes is larger than the limit of {} bytes",
+                size, MAX_FRAME
+            ),
+            FrameError::UnsupportedVersion(version) => write!(
+                f,
+                "unsupported framing version {}; expected {}",
+                version, FRAME_VERSION
+            ),
+            FrameError::NotUtf8(e) => write!(f, "frame is not UTF-8: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+/// A frame read from the other side.
+pub struct Frame {
+    pub framing: Framing,
+    /// The payload, or why it is malformed.
+    pub payload: Result<String, FrameError>,
+}
+
+/// Reads a frame. Errors are I/O errors, like the other side hanging up; malformed frames are
+/// read as frames with a `FrameError` for a payload, so that they can be answered.
+pub fn read(mut reader: impl Read) -> io::Result<Frame> {
+    let mut header = [0; 4];
+    reader.read_exact(&mut header)?;
+    let (framing, size) = match header {
+        [a, b, c, version] if [a, b, c] == *MAGIC => {
+            if version != FRAME_VERSION {
+                return Ok(Frame {
+                    framing: Framing::Tagged,
+                    payload: Err(FrameError::UnsupportedVersion(version)),
+                });
+            }
+            let mut size = [0; 4];
+            reader.read_exact(&mut size)?;
+            (Framing::Tagged, u32::from_be_bytes(size))
+        }
+        size => (Framing::Bare, u32::from_be_bytes(size)),
+    };
+    // Don't take the other side's word for how much memory to allocate.
+    if size as usize > MAX_FRAME {
+        return Ok(Frame {
+            framing,
+            payload: Err(FrameError::TooLarge(size.into())),
+        });
+    }
+
+    let mut payload = vec![0; size as usize];
+    reader.read_exact(&mut payload)?;
+    Ok(Frame {
+        framing,
+        payload: String::from_utf8(payload).map_err(FrameError::NotUtf8),
+    })
+}
 
-    writer.write_all(&msg_buf)?;
+/// Writes `payload` as a frame. Payloads larger than `MAX_FRAME` are rejected, since the other
+/// side would reject them anyway.
+pub fn write(mut writer: impl Write, framing: Framing, payload: &str) -> Result<()> {
+    if payload.len() > MAX_FRAME {
+        return Err(FrameError::TooLarge(payload.len() as u64).into());
+    }
+    let mut frame = Vec::with_capacity(8 + payload.len());
+    if framing == Framing::Tagged {
+        frame.extend_from_slice(MAGIC);
+        frame.push(FRAME_VERSION);
+    }
+    frame.extend_from_slice(&u32::to_be_bytes(payload.len() as u32));
+    frame.extend_from_slice(payload.as_bytes());
+
+    writer.write_all(&frame)?;
     Ok(())
 }
+
+/// The version of the JSON request/response envelope.
+pub const VERSION: u32 = 1;
+
+/// A request to the server, as a JSON envelope:
+///
+/// ```json
+/// { "version": 1, "id": "abc", "token": "...", "kind": "twoslash", "code": "fn main() {}", "options": {} }
+/// ```
+///
+/// For compatibility, frames that are not JSON envelopes are treated as code to analyze with
+/// default options (or, for `Shutdown <uuid>`, as a request to shut down).
+#[derive(Deserialize)]
+pub struct Request {
+    pub version: u32,
+    /// Echoed back on the response, so that clients can match up requests and responses.
+    #[serde(default)]
+    pub id: Option<String>,
+    /// The server's secret, which must be given unless the server was started without one.
+    #[serde(default)]
+    pub token: Option<String>,
+    #[serde(flatten)]
+    pub body: RequestBody,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "kind", rename_all = "camelCase")]
+pub enum RequestBody {
+    Twoslash {
+        code: String,
+        #[serde(default)]
+        options: SnippetOptions,
+        /// The name of the profile to analyze the snippet with, or the default profile.
+        #[serde(default)]
+        profile: Option<String>,
+        /// How long the analysis may take, overriding the server's default.
+        #[serde(default, rename = "timeoutMs")]
+        timeout_ms: Option<u64>,
+    },
+    /// Analyzes several snippets, and answers with an array of their outcomes, in order: each a
+    /// `{ "result": ... }` or an `{ "error": ... }`. Snippets without their own profile or timeout
+    /// use the batch's.
+    Batch {
+        snippets: Vec<Snippet>,
+        #[serde(default)]
+        profile: Option<String>,
+        #[serde(default, rename = "timeoutMs")]
+        timeout_ms: Option<u64>,
+    },
+    /// Cancels the in-flight twoslash or batch request with the given id, which may have been sent
+    /// on another connection.
+    Cancel {
+        request: String,
+    },
+    /// Opens a document named `session`, which later requests edit, and answers with the twoslash
+    /// result for `code`. The session keeps the options and profile it was opened with.
+    OpenSession {
+        session: String,
+        code: String,
+        #[serde(default)]
+        options: SnippetOptions,
+        #[serde(default)]
+        profile: Option<String>,
+        #[serde(default, rename = "timeoutMs")]
+        timeout_ms: Option<u64>,
+    },
+    /// Applies `edits` to a session's document, and answers with the twoslash result for what it
+    /// has become.
+    EditSession {
+        session: String,
+        edits: Vec<Edit>,
+        #[serde(default, rename = "timeoutMs")]
+        timeout_ms: Option<u64>,
+    },
+    /// Closes a session, and answers with whether it was open.
+    CloseSession {
+        session: String,
+    },
+    /// Asks how the server is doing; see `Server::status`.
+    Status,
+    Shutdown {
+        uuid: String,
+    },
+}
+
+/// One snippet of a batch request; its fields are like a twoslash request's.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Snippet {
+    pub code: String,
+    #[serde(default)]
+    pub options: SnippetOptions,
+    #[serde(default)]
+    pub profile: Option<String>,
+    #[serde(default)]
+    pub timeout_ms: Option<u64>,
+}
+
+/// A change to a session's document: the text in `range` is replaced with `text`.
+#[derive(Deserialize)]
+pub struct Edit {
+    pub range: EditRange,
+    pub text: String,
+}
+
+/// Offsets into a document, measured in the session's `encoding`. Each edit's range is into the
+/// document as the edits before it left it.
+#[derive(Deserialize)]
+pub struct EditRange {
+    pub start: u32,
+    pub end: u32,
+}
+
+#[cfg(test)]
+impl Edit {
+    pub fn new(start: u32, end: u32, text: &str) -> Edit {
+        Edit {
+            range: EditRange { start, end },
+            text: text.to_string(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ErrorResponse {
+    /// A stable, machine-readable name for the kind of error.
+    pub kind: &'static str,
+    pub message: String,
+    /// Whatever results were ready, for requests that timed out or were cancelled.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub partial: Option<serde_json::Value>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Outcome {
+    Result(serde_json::Value),
+    Error(ErrorResponse),
+}
+
+/// A response from the server, as a JSON envelope with either a `result` or an `error`:
+///
+/// ```json
+/// { "version": 1, "id": "abc", "result": { "code": "fn main() {}", ... } }
+/// { "version": 1, "id": "abc", "error": { "kind": "badRequest", "message": "..." } }
+/// ```
+#[derive(Serialize)]
+pub struct Response {
+    pub version: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(flatten)]
+    pub outcome: Outcome,
+}
+
+impl Response {
+    pub fn result(id: Option<String>, result: impl Serialize) -> Result<Self> {
+        Ok(Response {
+            version: VERSION,
+            id,
+            outcome: Outcome::Result(serde_json::to_value(result)?),
+        })
+    }
+
+    pub fn error(id: Option<String>, kind: &'static str, message: impl ToString) -> Self {
+        Response {
+            version: VERSION,
+            id,
+            outcome: Outcome::Error(ErrorResponse {
+                kind,
+                message: message.to_string(),
+                partial: None,
+            }),
+        }
+    }
+
+    /// An error response that carries partial results.
+    pub fn partial(
+        id: Option<String>,
+        kind: &'static str,
+        message: impl ToString,
+        partial: impl Serialize,
+    ) -> Result<Self> {
+        Ok(Response {
+            version: VERSION,
+            id,
+            outcome: Outcome::Error(ErrorResponse {
+                kind,
+                message: message.to_string(),
+                partial: Some(serde_json::to_value(partial)?),
+            }),
+        })
+    }
+}
+
+/// A message read from a client.
+pub enum Message {
+    Request(Request),
+    /// A JSON object that is not a valid request, and why. Its `id` is kept, if it has one, so
+    /// that the error can be matched up with the request.
+    Invalid {
+        id: Option<String>,
+        error: String,
+    },
+    /// A frame that is not JSON; either code, or a shutdown message.
+    Legacy(String),
+}
+
+impl Message {
+    pub fn parse(frame: String) -> Message {
+        // Rust code is never a JSON object, so anything that is must be meant as an envelope.
+        match serde_json::from_str::<serde_json::Value>(&frame) {
+            Ok(value @ serde_json::Value::Object(_)) => {
+                let id = value
+                    .get("id")
+                    .and_then(|id| id.as_str())
+                    .map(str::to_string);
+                match serde_json::from_value(value) {
+                    Ok(request) => Message::Request(request),
+                    Err(e) => Message::Invalid {
+                        id,
+                        error: e.to_string(),
+                    },
+                }
+            }
+            _ => Message::Legacy(frame),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{read, write, FrameError, Framing, Message, FRAME_VERSION, MAGIC, MAX_FRAME};
+
+    #[test]
+    fn test_round_trips() {
+        for framing in [Framing::Tagged, Framing::Bare] {
+            let mut buf = vec![];
+            write(&mut buf, framing, "let café = 1;").unwrap();
+            let frame = read(buf.as_slice()).unwrap();
+            assert_eq!(frame.payload.unwrap(), "let café = 1;");
+            assert_eq!(frame.framing, framing);
+        }
+    }
+
+    #[test]
+    fn test_rejects_oversize_frames_unread() {
+        let mut frame = MAGIC.to_vec();
+        frame.push(FRAME_VERSION);
+        frame.extend_from_slice(&u32::MAX.to_be_bytes());
+        let e = read(frame.as_slice()).unwrap().payload.unwrap_err();
+        assert_eq!(e.kind(), "frameTooLarge");
+
+        let e = write(vec![], Framing::Tagged, &"x".repeat(MAX_FRAME + 1)).unwrap_err();
+        assert!(e.is::<FrameError>());
+    }
+
+    #[test]
+    fn test_rejects_unknown_framing_versions() {
+        let frame = [b'T', b'W', b'S', FRAME_VERSION + 1, 0, 0, 0, 0];
+        let e = read(frame.as_slice()).unwrap().payload.unwrap_err();
+        assert_eq!(e.kind(), "unsupportedFraming");
+        assert!(!e.resumable());
+    }
+
+    #[test]
+    fn test_keeps_the_id_of_invalid_requests() {
+        let frame =
+            r#"{ "version": 1, "id": "abc", "kind": "twoslash", "code": "", "timeoutMs": -1 }"#;
+        match Message::parse(frame.to_string()) {
+            Message::Invalid { id, .. } => assert_eq!(id.as_deref(), Some("abc")),
+            _ => panic!("parsed an invalid request"),
+        }
+        match Message::parse(r#"{ "version": 1, "kind": "frobnicate" }"#.to_string()) {
+            Message::Invalid { id, .. } => assert_eq!(id, None),
+            _ => panic!("parsed an invalid request"),
+        }
+    }
+
+    #[test]
+    fn test_reads_past_invalid_utf8() {
+        let mut frames = vec![];
+        frames.extend_from_slice(&2u32.to_be_bytes());
+        frames.extend_from_slice(&[0xc3, 0x28]);
+        write(&mut frames, Framing::Bare, "ok").unwrap();
+
+        let mut reader = frames.as_slice();
+        let e = read(&mut reader).unwrap().payload.unwrap_err();
+        assert!(e.resumable());
+        assert_eq!(read(&mut reader).unwrap().payload.unwrap(), "ok");
+    }
+}
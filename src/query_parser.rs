@@ -20,6 +20,11 @@ lazy_static! {
     ];
 }
 
+/// Whether `line` is a query, which `find_queries` removes from the source.
+pub fn is_query(line: &str) -> bool {
+    PARSERS.iter().any(|(_, parser, _)| parser.is_match(line))
+}
+
 pub fn find_queries(src: &str) -> (String, Vec<(QueryKind, TextSize)>) {
     let mut queries = vec![];
     let mut removed_lines = 0;
@@ -0,0 +1,178 @@
+//! Executes a built snippet under a timeout, capturing a bounded amount of its output.
+//! Child processes, the snippet's and cargo's alike, are killed when their request is cancelled.
+
+use std::io::Read;
+use std::path::Path;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use lazy_static::lazy_static;
+use regex::Regex;
+
+use crate::cancel::{Cancel, Interrupted, Reason};
+
+/// How long a snippet may run before it is killed.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+/// How many bytes of each of stdout and stderr are kept.
+pub const DEFAULT_MAX_OUTPUT: usize = 64 * 1024;
+/// How often to check whether a running process should be stopped.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+lazy_static! {
+    // Older toolchains print `panicked at 'msg', file:line:col`, newer ones print
+    // `panicked at file:line:col:\nmsg`.
+    static ref PANIC: Regex = Regex::new(
+        r#"thread '[^']*' panicked at (?:'(?P<quoted>[^\n]*)', [^\n]*|[^\n]*:\n(?P<message>[^\n]*))"#
+    )
+    .unwrap();
+}
+
+pub struct Output {
+    pub stdout: String,
+    pub stderr: String,
+    /// `None` if the process was killed by a signal (including because it timed out).
+    pub exit_code: Option<i32>,
+    pub timed_out: bool,
+    /// Was either of stdout or stderr cut off at the output limit?
+    pub truncated: bool,
+}
+
+impl Output {
+    /// The message the process panicked with, if it did.
+    pub fn panic_message(&self) -> Option<String> {
+        let capture = PANIC.captures(&self.stderr)?;
+        capture
+            .name("quoted")
+            .or_else(|| capture.name("message"))
+            .map(|m| m.as_str().to_string())
+    }
+}
+
+/// Reads up to `max` bytes from `pipe` on another thread, draining (and discarding) the rest so
+/// that the child never blocks on a full pipe. Yields the captured text and whether it was cut off.
+pub fn capture(
+    pipe: Option<impl Read + Send + 'static>,
+    max: usize,
+) -> thread::JoinHandle<(String, bool)> {
+    thread::spawn(move || {
+        let mut pipe = match pipe {
+            Some(pipe) => pipe,
+            None => return (String::new(), false),
+        };
+        let mut buf = Vec::new();
+        let _ = (&mut pipe).take(max as u64).read_to_end(&mut buf);
+        let rest = std::io::copy(&mut pipe, &mut std::io::sink()).unwrap_or_default();
+        (String::from_utf8_lossy(&buf).into_owned(), rest > 0)
+    })
+}
+
+/// Why `wait_or_kill` killed a process.
+pub enum Killed {
+    /// The deadline passed.
+    Deadline,
+    /// The request the process was for was cancelled, or timed out.
+    Cancelled(Reason),
+}
+
+/// Waits for `child` to exit, killing it if `deadline` passes or `cancel` fires first. Returns its
+/// exit status, and why it was killed if it was.
+pub fn wait_or_kill(
+    child: &mut Child,
+    deadline: Option<Instant>,
+    cancel: &Cancel,
+) -> Result<(ExitStatus, Option<Killed>)> {
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok((status, None));
+        }
+        let killed = match cancel.reason() {
+            Some(reason) => Some(Killed::Cancelled(reason)),
+            None if deadline.is_some_and(|deadline| Instant::now() >= deadline) => {
+                Some(Killed::Deadline)
+            }
+            None => None,
+        };
+        if let Some(killed) = killed {
+            // The process may exit between the check above and here; either way, reap it.
+            let _ = child.kill();
+            return Ok((child.wait()?, Some(killed)));
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// The error for a process killed because its request was cancelled.
+pub fn interrupted(reason: Reason) -> anyhow::Error {
+    Interrupted {
+        reason: Some(reason),
+        partial: None,
+    }
+    .into()
+}
+
+/// Runs `executable`, killing it if it runs longer than `timeout` and keeping at most
+/// `max_output` bytes of each output stream. If `cancel` fires first, it is killed too, and the
+/// result is an `Interrupted` error.
+pub fn run(
+    executable: &Path,
+    timeout: Duration,
+    max_output: usize,
+    cancel: &Cancel,
+) -> Result<Output> {
+    let mut child = Command::new(executable)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    let stdout = capture(child.stdout.take(), max_output);
+    let stderr = capture(child.stderr.take(), max_output);
+    let (status, killed) = wait_or_kill(&mut child, Some(Instant::now() + timeout), cancel)?;
+    let timed_out = match killed {
+        Some(Killed::Cancelled(reason)) => return Err(interrupted(reason)),
+        Some(Killed::Deadline) => true,
+        None => false,
+    };
+    let (stdout, stdout_truncated) = stdout.join().unwrap_or_default();
+    let (stderr, stderr_truncated) = stderr.join().unwrap_or_default();
+
+    Ok(Output {
+        stdout,
+        stderr,
+        exit_code: status.code(),
+        timed_out,
+        truncated: stdout_truncated || stderr_truncated,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::Output;
+
+    fn output_with_stderr(stderr: &str) -> Output {
+        Output {
+            stdout: String::new(),
+            stderr: stderr.to_string(),
+            exit_code: Some(101),
+            timed_out: false,
+            truncated: false,
+        }
+    }
+
+    #[test]
+    fn test_panic_message() {
+        let old = output_with_stderr(
+            "thread 'main' panicked at 'boom', src/main.rs:2:5\nnote: run with `RUST_BACKTRACE=1`",
+        );
+        assert_eq!(old.panic_message(), Some("boom".to_string()));
+
+        let new = output_with_stderr(
+            "thread 'main' panicked at src/main.rs:2:5:\nboom\nnote: run with `RUST_BACKTRACE=1`",
+        );
+        assert_eq!(new.panic_message(), Some("boom".to_string()));
+
+        assert_eq!(output_with_stderr("hello").panic_message(), None);
+    }
+}
@@ -0,0 +1,149 @@
+//! Rustdoc code block attributes, and checking a snippet against what they say it should do.
+
+use crate::twoslash::{Execution, Expectation};
+
+/// The attributes of a rustdoc code block, like `compile_fail,E0308`.
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct CodeBlockAttributes {
+    /// The attributes as written, for reporting.
+    pub source: String,
+    /// Do not check the snippet at all.
+    pub ignore: bool,
+    /// Check that the snippet compiles, but do not run it.
+    pub no_run: bool,
+    /// Check that the snippet fails to compile.
+    pub compile_fail: bool,
+    /// Check that running the snippet panics.
+    pub should_panic: bool,
+    /// Error codes the snippet must fail with, like `E0308`. Only meaningful with `compile_fail`.
+    pub error_codes: Vec<String>,
+}
+
+impl CodeBlockAttributes {
+    /// Parses attributes separated by commas or whitespace, like rustdoc does. Attributes that do
+    /// not affect checking (like `rust` or `edition2018`) are ignored.
+    pub fn parse(attributes: &str) -> Self {
+        let mut result = CodeBlockAttributes {
+            source: attributes.trim().to_string(),
+            ..Default::default()
+        };
+        for attribute in attributes
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|attribute| !attribute.is_empty())
+        {
+            match attribute {
+                "ignore" => result.ignore = true,
+                "no_run" => result.no_run = true,
+                "compile_fail" => result.compile_fail = true,
+                "should_panic" => result.should_panic = true,
+                code if is_error_code(code) => result.error_codes.push(code.to_string()),
+                _ => {}
+            }
+        }
+        result
+    }
+
+    /// Should the snippet be run (if it was asked to be run)?
+    pub fn allows_run(&self) -> bool {
+        !(self.ignore || self.no_run || self.compile_fail)
+    }
+}
+
+fn is_error_code(s: &str) -> bool {
+    s.len() == 5 && s.starts_with('E') && s[1..].chars().all(|c| c.is_ascii_digit())
+}
+
+/// Checks the snippet's behavior against its attributes. `compile_errors` are the ids of the
+/// errors the snippet failed to compile with, and `execution` the result of running it, if it
+/// was run.
+pub fn check(
+    attributes: &CodeBlockAttributes,
+    compile_errors: &[String],
+    execution: Option<&Execution>,
+) -> Expectation {
+    let outcome = if attributes.ignore {
+        None
+    } else if attributes.compile_fail {
+        let missing_codes: Vec<_> = attributes
+            .error_codes
+            .iter()
+            .filter(|code| !compile_errors.contains(code))
+            .map(String::as_str)
+            .collect();
+        Some(if compile_errors.is_empty() {
+            Err("expected the snippet to fail to compile, but it compiled".to_string())
+        } else if !missing_codes.is_empty() {
+            Err(format!(
+                "expected the snippet to fail to compile with {}",
+                missing_codes.join(", ")
+            ))
+        } else {
+            Ok(())
+        })
+    } else if !compile_errors.is_empty() {
+        Some(Err(format!(
+            "expected the snippet to compile, but it failed with {}",
+            compile_errors.join(", ")
+        )))
+    } else if attributes.should_panic {
+        Some(match execution {
+            Some(Execution { panic: Some(_), .. }) => Ok(()),
+            Some(_) => Err("expected the snippet to panic, but it did not".to_string()),
+            None => Err("expected the snippet to panic, but it was not run".to_string()),
+        })
+    } else {
+        Some(match execution {
+            Some(Execution {
+                panic: Some(msg), ..
+            }) => Err(format!("the snippet panicked with '{}'", msg)),
+            Some(Execution {
+                timed_out: true, ..
+            }) => Err("the snippet timed out".to_string()),
+            _ => Ok(()),
+        })
+    };
+
+    Expectation {
+        attributes: attributes.source.clone(),
+        held: outcome.as_ref().map(Result::is_ok),
+        reason: outcome.and_then(Result::err),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{check, CodeBlockAttributes};
+
+    #[test]
+    fn test_parse_attributes() {
+        let attributes = CodeBlockAttributes::parse("rust,compile_fail, E0308");
+
+        assert!(attributes.compile_fail);
+        assert!(!attributes.ignore && !attributes.no_run && !attributes.should_panic);
+        assert_eq!(attributes.error_codes, vec!["E0308".to_string()]);
+        assert!(!attributes.allows_run());
+    }
+
+    #[test]
+    fn test_check_compile_fail() {
+        let attributes = CodeBlockAttributes::parse("compile_fail,E0308");
+
+        let held = check(&attributes, &["E0308".to_string()], None);
+        assert_eq!(held.held, Some(true));
+
+        let wrong_code = check(&attributes, &["E0425".to_string()], None);
+        assert_eq!(wrong_code.held, Some(false));
+
+        let compiled = check(&attributes, &[], None);
+        assert_eq!(compiled.held, Some(false));
+    }
+
+    #[test]
+    fn test_check_ignore() {
+        let attributes = CodeBlockAttributes::parse("ignore");
+
+        let expectation = check(&attributes, &["E0308".to_string()], None);
+        assert_eq!(expectation.held, None);
+        assert_eq!(expectation.reason, None);
+    }
+}
@@ -0,0 +1,907 @@
+//! Handles requests to a twoslash server, independently of how they are transported.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use serde_json::json;
+
+use crate::auth;
+use crate::cache::{self, MemoryCache};
+use crate::cancel::{Cancel, Interrupted, Reason};
+use crate::lifecycle;
+use crate::logging;
+use crate::options::SnippetOptions;
+use crate::pool::Pool;
+use crate::profiles::{Profile, DEFAULT_PROFILE};
+use crate::protocol::{
+    self, Edit, ErrorResponse, Message, Outcome, Request, RequestBody, Response, Snippet,
+};
+use crate::session::Session;
+use crate::stats::{self, Phases, Stats};
+use crate::twoslash::TwoSlash;
+
+/// How a server behaves, apart from how it analyzes snippets.
+pub struct ServerSettings {
+    pub uuid: String,
+    /// The secret clients must present with every request, if they must.
+    pub token: Option<String>,
+    /// How long analyses may take, unless a request says otherwise.
+    pub timeout: Option<Duration>,
+    /// How many results to keep in memory, so that unchanged snippets needn't be analyzed again.
+    pub cache_size: usize,
+    /// How many sessions may be open at once. Opening another closes the least recently used.
+    pub max_sessions: usize,
+    /// How long the server may go without requests before it shuts itself down.
+    pub idle_timeout: Option<Duration>,
+    /// A process whose exit the server shuts itself down after, like whatever started it.
+    pub parent: Option<u32>,
+}
+
+struct SessionEntry {
+    session: Arc<Mutex<Session>>,
+    last_used: Instant,
+}
+
+/// When the server last heard from a client, and how many twoslash requests it is working on.
+struct Activity {
+    last: Instant,
+    busy: usize,
+}
+
+pub struct Server {
+    settings: ServerSettings,
+    /// Each profile's settings, and the pool of workers analyzing snippets with them.
+    profiles: HashMap<String, (Profile, Pool)>,
+    /// Requests being analyzed, by id, so that they can be cancelled. The snippets of a batch
+    /// share its id.
+    in_flight: Mutex<HashMap<String, Vec<Cancel>>>,
+    stats: Arc<Stats>,
+    cache: MemoryCache,
+    /// The toolchain version results are cached under.
+    toolchain: String,
+    activity: Mutex<Activity>,
+    /// Open sessions, by name.
+    sessions: Mutex<HashMap<String, SessionEntry>>,
+    /// Numbers sessions' project directories.
+    next_session: AtomicU64,
+}
+
+/// How a twoslash request was handled, for the log.
+struct Trace {
+    profile: String,
+    /// The session whose document was analyzed, if any.
+    session: Option<String>,
+    code_hash: String,
+    code_bytes: usize,
+    cached: bool,
+    phases: Phases,
+}
+
+impl Trace {
+    fn new(profile: &str, code: &str) -> Self {
+        Trace {
+            profile: profile.to_string(),
+            session: None,
+            code_hash: cache::digest(code),
+            code_bytes: code.len(),
+            cached: false,
+            phases: vec![],
+        }
+    }
+}
+
+/// Why a twoslash request failed.
+pub struct Failure {
+    /// A stable, machine-readable name for the kind of failure; see `protocol::ErrorResponse`.
+    pub kind: &'static str,
+    pub message: String,
+    /// Whatever results were ready, if the analysis was interrupted.
+    pub partial: Option<TwoSlash>,
+}
+
+impl Failure {
+    fn new(kind: &'static str, message: impl ToString) -> Self {
+        Failure {
+            kind,
+            message: message.to_string(),
+            partial: None,
+        }
+    }
+}
+
+impl From<anyhow::Error> for Failure {
+    fn from(e: anyhow::Error) -> Self {
+        match e.downcast::<Interrupted>() {
+            Ok(interrupted) => Failure {
+                kind: match interrupted.reason {
+                    Some(Reason::Timeout) => "timeout",
+                    Some(Reason::Requested) => "cancelled",
+                    None => "interrupted",
+                },
+                message: interrupted.to_string(),
+                partial: interrupted.partial,
+            },
+            Err(e) => Failure::new("analysisFailed", e),
+        }
+    }
+}
+
+/// What to do after handling a frame.
+pub struct Reply {
+    /// The frame to send back, if any.
+    pub frame: Option<String>,
+    pub shutdown: bool,
+}
+
+impl Reply {
+    fn send(frame: String) -> Self {
+        Reply {
+            frame: Some(frame),
+            shutdown: false,
+        }
+    }
+
+    fn response(response: Response) -> Self {
+        match serde_json::to_string(&response) {
+            Ok(frame) => Reply::send(frame),
+            Err(e) => Reply::error(None, "internal", e),
+        }
+    }
+
+    pub fn error(id: Option<String>, kind: &'static str, message: impl ToString) -> Self {
+        // Error responses are always serializable.
+        Reply::send(serde_json::to_string(&Response::error(id, kind, message)).unwrap())
+    }
+
+    fn twoslash(id: Option<String>, result: Result<TwoSlash, Failure>) -> Self {
+        match result {
+            Ok(twoslash_result) => match Response::result(id.clone(), twoslash_result) {
+                Ok(response) => Reply::response(response),
+                Err(e) => Reply::error(id, "internal", e),
+            },
+            Err(failure) => Reply::failure(id, failure),
+        }
+    }
+
+    fn failure(id: Option<String>, failure: Failure) -> Self {
+        let Failure {
+            kind,
+            message,
+            partial,
+        } = failure;
+        match partial {
+            Some(partial) => match Response::partial(id.clone(), kind, message, partial) {
+                Ok(response) => Reply::response(response),
+                Err(e) => Reply::error(id, "internal", e),
+            },
+            None => Reply::error(id, kind, message),
+        }
+    }
+}
+
+/// The outcome of one snippet of a batch.
+fn outcome(result: Result<TwoSlash, Failure>) -> Outcome {
+    let failure = match result.map(serde_json::to_value) {
+        Ok(Ok(twoslash_result)) => return Outcome::Result(twoslash_result),
+        Ok(Err(e)) => Failure::new("internal", e),
+        Err(failure) => failure,
+    };
+    Outcome::Error(ErrorResponse {
+        kind: failure.kind,
+        message: failure.message,
+        partial: failure
+            .partial
+            .and_then(|partial| serde_json::to_value(partial).ok()),
+    })
+}
+
+impl Server {
+    /// Starts a pool of workers for each profile. There must be a `default` profile.
+    pub fn new(profiles: Vec<Profile>, settings: ServerSettings) -> Result<Self> {
+        let stats = Arc::new(Stats::default());
+        let profiles = profiles
+            .into_iter()
+            .map(|profile| {
+                let pool = Pool::new(&profile.settings, profile.workers, &stats)?;
+                Ok((profile.name.clone(), (profile, pool)))
+            })
+            .collect::<Result<HashMap<_, _>>>()?;
+        if !profiles.contains_key(DEFAULT_PROFILE) {
+            anyhow::bail!("there is no {} profile", DEFAULT_PROFILE);
+        }
+        Ok(Server {
+            cache: MemoryCache::new(settings.cache_size),
+            settings,
+            profiles,
+            in_flight: Mutex::new(HashMap::new()),
+            stats,
+            toolchain: cache::toolchain_version(),
+            activity: Mutex::new(Activity {
+                last: Instant::now(),
+                busy: 0,
+            }),
+            sessions: Mutex::new(HashMap::new()),
+            next_session: AtomicU64::new(0),
+        })
+    }
+
+    /// Whether a request carrying `token` may be served. Only requests that may be served keep the
+    /// server from being idle, so that strangers can't keep an orphaned server alive.
+    pub fn authorized(&self, token: Option<&str>) -> bool {
+        let authorized = match (&self.settings.token, token) {
+            (None, _) => true,
+            (Some(expected), Some(given)) => auth::tokens_match(expected, given),
+            (Some(_), None) => false,
+        };
+        match authorized {
+            true => self.activity.lock().unwrap().last = Instant::now(),
+            false => logging::warn(
+                "unauthorized request",
+                json!({ "hadToken": token.is_some() }),
+            ),
+        }
+        authorized
+    }
+
+    /// Why the server should shut itself down, if it should: it has been idle for too long, or
+    /// its parent has exited.
+    pub fn expired(&self) -> Option<String> {
+        if let Some(parent) = self.settings.parent {
+            if !lifecycle::alive(parent) {
+                return Some(format!("parent process {} exited", parent));
+            }
+        }
+        let idle_timeout = self.settings.idle_timeout?;
+        let activity = self.activity.lock().unwrap();
+        match activity.busy == 0 && activity.last.elapsed() >= idle_timeout {
+            true => Some(format!(
+                "no requests for {}s",
+                activity.last.elapsed().as_secs()
+            )),
+            false => None,
+        }
+    }
+
+    /// Whether `uuid` is this server's, which clients must know to shut it down.
+    pub fn is_uuid(&self, uuid: &str) -> bool {
+        uuid == self.settings.uuid
+    }
+
+    pub fn profile_names(&self) -> Vec<&str> {
+        let mut names: Vec<_> = self.profiles.keys().map(String::as_str).collect();
+        names.sort_unstable();
+        names
+    }
+
+    /// How the server is doing: its uptime, settings, how many requests it has served and how
+    /// fast, its memory use and its last error.
+    pub fn status(&self) -> serde_json::Value {
+        let profiles: serde_json::Map<_, _> = self
+            .profiles
+            .iter()
+            .map(|(name, (profile, _))| {
+                let settings = &profile.settings;
+                let status = json!({
+                    "kind": settings.kind,
+                    "projectName": settings.project_name,
+                    "clippy": settings.use_clippy,
+                    "procMacros": settings.with_proc_macros,
+                    "dependencies": settings.dependencies,
+                    "edition": profile.edition,
+                    "workers": profile.workers,
+                });
+                (name.clone(), status)
+            })
+            .collect();
+        let mut status = json!(self.stats.snapshot());
+        status["profiles"] = profiles.into();
+        let timeout = self.settings.timeout;
+        status["timeoutMs"] = json!(timeout.map(|timeout| timeout.as_millis() as u64));
+        status["toolchain"] = json!(self.toolchain);
+        let mut sessions: Vec<_> = self.sessions.lock().unwrap().keys().cloned().collect();
+        sessions.sort_unstable();
+        status["sessions"] = json!(sessions);
+        status
+    }
+
+    /// Analyzes `code` with the named profile, or the default one. Requests with an `id` can be
+    /// cancelled by it while they are being analyzed. `timeout_ms` overrides the server's timeout.
+    pub fn twoslash(
+        &self,
+        id: Option<&str>,
+        code: String,
+        options: SnippetOptions,
+        profile: Option<&str>,
+        timeout_ms: Option<u64>,
+    ) -> Result<TwoSlash, Failure> {
+        let trace = Trace::new(profile.unwrap_or(DEFAULT_PROFILE), &code);
+        self.traced(id, trace, |trace| {
+            self.twoslash_with_profile(id, code, options, profile, timeout_ms, trace)
+        })
+    }
+
+    /// Runs `analyze`, and records it in the stats and the log as a twoslash request.
+    fn traced(
+        &self,
+        id: Option<&str>,
+        mut trace: Trace,
+        analyze: impl FnOnce(&mut Trace) -> Result<TwoSlash, Failure>,
+    ) -> Result<TwoSlash, Failure> {
+        let start = Instant::now();
+        self.activity.lock().unwrap().busy += 1;
+        let result = analyze(&mut trace);
+        {
+            // Idle time counts from when the last analysis finished.
+            let mut activity = self.activity.lock().unwrap();
+            activity.busy -= 1;
+            activity.last = Instant::now();
+        }
+        let duration = start.elapsed();
+        let error = result
+            .as_ref()
+            .err()
+            .map(|failure| (failure.kind, failure.message.as_str()));
+        self.stats.record_request(duration, error);
+
+        let phases_ms: HashMap<_, _> = trace
+            .phases
+            .iter()
+            .map(|(phase, duration)| (*phase, stats::millis(*duration)))
+            .collect();
+        let mut fields = json!({
+            "id": id,
+            "profile": trace.profile,
+            "session": trace.session,
+            "codeHash": trace.code_hash,
+            "codeBytes": trace.code_bytes,
+            "cached": trace.cached,
+            "durationMs": stats::millis(duration),
+            "phasesMs": phases_ms,
+            "outcome": error.map_or("ok", |(kind, _)| kind),
+        });
+        match error {
+            None => logging::info("twoslash", fields),
+            Some((_, message)) => {
+                fields["error"] = json!(message);
+                logging::warn("twoslash", fields);
+            }
+        }
+        result
+    }
+
+    fn twoslash_with_profile(
+        &self,
+        id: Option<&str>,
+        code: String,
+        options: SnippetOptions,
+        profile: Option<&str>,
+        timeout_ms: Option<u64>,
+        trace: &mut Trace,
+    ) -> Result<TwoSlash, Failure> {
+        let profile = profile.unwrap_or(DEFAULT_PROFILE);
+        let (profile, pool) = match self.profiles.get(profile) {
+            Some(entry) => entry,
+            None => {
+                let message = format!("there is no profile named {:?}", profile);
+                return Err(Failure::new("unknownProfile", message));
+            }
+        };
+        let options = profile.options(options);
+        let key = cache::key(&code, &options, &profile.settings, &self.toolchain);
+        if let Some(result) = self.cache.get(&key) {
+            self.stats.record_cache_hit();
+            trace.cached = true;
+            return Ok(result);
+        }
+        let timeout = self.timeout(timeout_ms);
+        let result = self.cancellable(id, trace, |cancel| {
+            pool.twoslash(code, options, timeout, cancel)
+        })?;
+        if cache::cacheable(&result) {
+            self.cache.insert(key, result.clone());
+        }
+        Ok(result)
+    }
+
+    /// How long an analysis may take, given how long the request says it may.
+    fn timeout(&self, timeout_ms: Option<u64>) -> Option<Duration> {
+        timeout_ms
+            .map(Duration::from_millis)
+            .or(self.settings.timeout)
+    }
+
+    /// Runs `analyze` with a `Cancel` that cancels it when a request to cancel `id` comes in.
+    fn cancellable(
+        &self,
+        id: Option<&str>,
+        trace: &mut Trace,
+        analyze: impl FnOnce(Cancel) -> (Result<TwoSlash>, Phases),
+    ) -> Result<TwoSlash, Failure> {
+        let cancel = Cancel::default();
+        if let Some(id) = id {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            in_flight
+                .entry(id.to_string())
+                .or_default()
+                .push(cancel.clone());
+        }
+        let (result, phases) = analyze(cancel.clone());
+        trace.phases = phases;
+        if let Some(id) = id {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            if let Some(cancels) = in_flight.get_mut(id) {
+                cancels.retain(|other| !other.same(&cancel));
+                if cancels.is_empty() {
+                    in_flight.remove(id);
+                }
+            }
+        }
+        Ok(result?)
+    }
+
+    /// Opens a session named `name` on `code`, and analyzes it.
+    pub fn open_session(
+        &self,
+        id: Option<&str>,
+        name: String,
+        code: String,
+        options: SnippetOptions,
+        profile: Option<&str>,
+        timeout_ms: Option<u64>,
+    ) -> Result<TwoSlash, Failure> {
+        let session_exists = || {
+            let message = format!("there is already a session named {:?}", name);
+            Failure::new("sessionExists", message)
+        };
+        if self.sessions.lock().unwrap().contains_key(&name) {
+            return Err(session_exists());
+        }
+        let profile = profile.unwrap_or(DEFAULT_PROFILE);
+        let profile = match self.profiles.get(profile) {
+            Some((profile, _)) => profile,
+            None => {
+                let message = format!("there is no profile named {:?}", profile);
+                return Err(Failure::new("unknownProfile", message));
+            }
+        };
+
+        // Scaffolding a project can take a while, so do it before taking the lock.
+        let number = self.next_session.fetch_add(1, Ordering::SeqCst);
+        let dir = profile.settings.dir.join(format!("session-{}", number));
+        let session = Session::open(profile, options, code, dir, &self.stats)?;
+        let evicted = {
+            let mut sessions = self.sessions.lock().unwrap();
+            if sessions.contains_key(&name) {
+                return Err(session_exists());
+            }
+            let oldest = sessions
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(oldest, _)| oldest.clone());
+            let evicted = match sessions.len() >= self.settings.max_sessions.max(1) {
+                true => oldest.and_then(|oldest| sessions.remove_entry(&oldest)),
+                false => None,
+            };
+            let entry = SessionEntry {
+                session: Arc::new(Mutex::new(session)),
+                last_used: Instant::now(),
+            };
+            sessions.insert(name.clone(), entry);
+            evicted
+        };
+        // Closing a session waits for its worker, so do it after letting go of the lock.
+        if let Some((evicted, _entry)) = evicted {
+            logging::info("session evicted", json!({ "session": evicted }));
+        }
+        self.session_twoslash(id, &name, None, timeout_ms)
+    }
+
+    /// Applies `edits` to a session's document, and analyzes what it has become.
+    pub fn edit_session(
+        &self,
+        id: Option<&str>,
+        name: &str,
+        edits: &[Edit],
+        timeout_ms: Option<u64>,
+    ) -> Result<TwoSlash, Failure> {
+        self.session_twoslash(id, name, Some(edits), timeout_ms)
+    }
+
+    fn session_twoslash(
+        &self,
+        id: Option<&str>,
+        name: &str,
+        edits: Option<&[Edit]>,
+        timeout_ms: Option<u64>,
+    ) -> Result<TwoSlash, Failure> {
+        let session = match self.sessions.lock().unwrap().get_mut(name) {
+            Some(entry) => {
+                entry.last_used = Instant::now();
+                Arc::clone(&entry.session)
+            }
+            None => {
+                let message = format!("there is no session named {:?}", name);
+                return Err(Failure::new("unknownSession", message));
+            }
+        };
+        // Edits to a session are analyzed one at a time, in the order they arrive.
+        let mut session = session.lock().unwrap();
+        if let Some(edits) = edits {
+            session
+                .edit(edits)
+                .map_err(|e| Failure::new("badEdit", e))?;
+        }
+
+        let timeout = self.timeout(timeout_ms);
+        let mut trace = Trace::new(&session.profile, &session.text);
+        trace.session = Some(name.to_string());
+        self.traced(id, trace, |trace| {
+            self.cancellable(id, trace, |cancel| session.twoslash(timeout, cancel))
+        })
+    }
+
+    /// Closes a session. Returns whether it was open.
+    pub fn close_session(&self, name: &str) -> bool {
+        let closed = self.sessions.lock().unwrap().remove(name);
+        closed.is_some()
+    }
+
+    /// Analyzes several snippets, concurrently as far as their profiles' pools allow, and returns
+    /// each one's outcome in order. Cancelling the batch's `id` cancels all of its snippets.
+    pub fn batch(
+        &self,
+        id: Option<&str>,
+        snippets: Vec<Snippet>,
+        profile: Option<&str>,
+        timeout_ms: Option<u64>,
+    ) -> Vec<Outcome> {
+        let start = Instant::now();
+        let size = snippets.len();
+        // More threads than there are workers would only wait in the pools' queues.
+        let concurrency = self
+            .profiles
+            .values()
+            .map(|(profile, _)| profile.workers.max(1))
+            .sum::<usize>()
+            .min(size);
+        let snippets = Mutex::new(snippets.into_iter().enumerate());
+        let results = Mutex::new((0..size).map(|_| None).collect::<Vec<_>>());
+        thread::scope(|scope| {
+            let analyses: Vec<_> = (0..concurrency)
+                .map(|_| {
+                    scope.spawn(|| loop {
+                        let next = snippets
+                            .lock()
+                            .unwrap_or_else(PoisonError::into_inner)
+                            .next();
+                        let (index, snippet) = match next {
+                            Some(next) => next,
+                            None => return,
+                        };
+                        let result = self.twoslash(
+                            id,
+                            snippet.code,
+                            snippet.options,
+                            snippet.profile.as_deref().or(profile),
+                            snippet.timeout_ms.or(timeout_ms),
+                        );
+                        results.lock().unwrap_or_else(PoisonError::into_inner)[index] =
+                            Some(outcome(result));
+                    })
+                })
+                .collect();
+            // Joining the threads ourselves keeps a panic in one from taking down the batch.
+            for analysis in analyses {
+                let _ = analysis.join();
+            }
+        });
+        let outcomes: Vec<_> = results
+            .into_inner()
+            .unwrap_or_else(PoisonError::into_inner)
+            .into_iter()
+            .map(|result| {
+                result.unwrap_or_else(|| {
+                    outcome(Err(Failure::new(
+                        "internal",
+                        "the analysis thread panicked",
+                    )))
+                })
+            })
+            .collect();
+        let failures = outcomes
+            .iter()
+            .filter(|outcome| matches!(outcome, Outcome::Error(_)))
+            .count();
+        logging::info(
+            "batch",
+            json!({
+                "id": id,
+                "snippets": size,
+                "failures": failures,
+                "durationMs": stats::millis(start.elapsed()),
+            }),
+        );
+        outcomes
+    }
+
+    /// Cancels the in-flight requests with id `request`. Returns whether there were any.
+    fn cancel(&self, request: &str) -> bool {
+        match self.in_flight.lock().unwrap().get(request) {
+            Some(cancels) => {
+                for cancel in cancels {
+                    cancel.cancel(Reason::Requested);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Handles a frame from a client. Failures are reported to the client rather than returned,
+    /// so that one bad request does not take down the server. Frames may be handled concurrently.
+    pub fn handle(&self, frame: String) -> Reply {
+        match Message::parse(frame) {
+            // Legacy messages can't carry a token.
+            Message::Legacy(_) if !self.authorized(None) => Reply::error(
+                None,
+                "unauthorized",
+                "requests must be envelopes with a token",
+            ),
+            Message::Legacy(message) if message == format!("Shutdown {}", self.settings.uuid) => {
+                Reply {
+                    frame: None,
+                    shutdown: true,
+                }
+            }
+            Message::Legacy(code) => {
+                // The only other legacy messages we permit are code that should be analyzed for
+                // twoslash-ing.
+                match self.twoslash(None, code, SnippetOptions::default(), None, None) {
+                    Ok(twoslash_result) => match serde_json::to_string(&twoslash_result) {
+                        Ok(frame) => Reply::send(frame),
+                        Err(e) => Reply::error(None, "internal", e),
+                    },
+                    Err(failure) => Reply::error(None, failure.kind, failure.message),
+                }
+            }
+            Message::Invalid { id, error } => Reply::error(id, "badRequest", error),
+            Message::Request(Request { id, token, .. }) if !self.authorized(token.as_deref()) => {
+                Reply::error(id, "unauthorized", "missing or wrong token")
+            }
+            Message::Request(Request { version, id, .. }) if version != protocol::VERSION => {
+                let message = format!(
+                    "unsupported protocol version {}; expected {}",
+                    version,
+                    protocol::VERSION
+                );
+                Reply::error(id, "unsupportedVersion", message)
+            }
+            Message::Request(Request {
+                id,
+                body: RequestBody::Shutdown { uuid },
+                ..
+            }) => match self.is_uuid(&uuid) {
+                true => Reply {
+                    shutdown: true,
+                    ..Reply::response(Response::result(id, ()).unwrap())
+                },
+                false => Reply::error(id, "badShutdown", "wrong server uuid"),
+            },
+            Message::Request(Request {
+                id,
+                body:
+                    RequestBody::Twoslash {
+                        code,
+                        options,
+                        profile,
+                        timeout_ms,
+                    },
+                ..
+            }) => {
+                let result =
+                    self.twoslash(id.as_deref(), code, options, profile.as_deref(), timeout_ms);
+                Reply::twoslash(id, result)
+            }
+            Message::Request(Request {
+                id,
+                body:
+                    RequestBody::OpenSession {
+                        session,
+                        code,
+                        options,
+                        profile,
+                        timeout_ms,
+                    },
+                ..
+            }) => {
+                let result = self.open_session(
+                    id.as_deref(),
+                    session,
+                    code,
+                    options,
+                    profile.as_deref(),
+                    timeout_ms,
+                );
+                Reply::twoslash(id, result)
+            }
+            Message::Request(Request {
+                id,
+                body:
+                    RequestBody::EditSession {
+                        session,
+                        edits,
+                        timeout_ms,
+                    },
+                ..
+            }) => {
+                let result = self.edit_session(id.as_deref(), &session, &edits, timeout_ms);
+                Reply::twoslash(id, result)
+            }
+            Message::Request(Request {
+                id,
+                body: RequestBody::CloseSession { session },
+                ..
+            }) => Reply::response(Response::result(id, self.close_session(&session)).unwrap()),
+            Message::Request(Request {
+                id,
+                body:
+                    RequestBody::Batch {
+                        snippets,
+                        profile,
+                        timeout_ms,
+                    },
+                ..
+            }) => {
+                let outcomes = self.batch(id.as_deref(), snippets, profile.as_deref(), timeout_ms);
+                match Response::result(id.clone(), outcomes) {
+                    Ok(response) => Reply::response(response),
+                    Err(e) => Reply::error(id, "internal", e),
+                }
+            }
+            Message::Request(Request {
+                id,
+                body: RequestBody::Status,
+                ..
+            }) => Reply::response(Response::result(id, self.status()).unwrap()),
+            Message::Request(Request {
+                id,
+                body: RequestBody::Cancel { request },
+                ..
+            }) => {
+                let cancelled = self.cancel(&request);
+                Reply::response(Response::result(id, cancelled).unwrap())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use serde_json::json;
+    use tempfile::TempDir;
+
+    use super::{Failure, Server, ServerSettings};
+    use crate::options::SnippetOptions;
+    use crate::profiles::{Profile, DEFAULT_PROFILE};
+    use crate::project::{ProjectKind, ProjectSettings};
+    use crate::protocol::{Edit, Outcome, Snippet};
+    use crate::twoslash::TwoSlash;
+
+    /// A server that analyzes snippets on their own, on one worker, with room for two sessions.
+    fn server(dir: &Path) -> Server {
+        let settings = ProjectSettings {
+            kind: ProjectKind::SingleFile,
+            project_name: "twoslash_test".to_string(),
+            dir: dir.to_path_buf(),
+            use_clippy: false,
+            with_proc_macros: false,
+            dependencies: String::new(),
+        };
+        let profile = Profile {
+            name: DEFAULT_PROFILE.to_string(),
+            settings,
+            edition: None,
+            workers: 1,
+        };
+        let settings = ServerSettings {
+            uuid: "test".to_string(),
+            token: None,
+            timeout: None,
+            cache_size: 0,
+            max_sessions: 2,
+            idle_timeout: None,
+            parent: None,
+        };
+        Server::new(vec![profile], settings).unwrap()
+    }
+
+    fn snippet(code: &str, profile: Option<&str>) -> Snippet {
+        Snippet {
+            code: code.to_string(),
+            options: SnippetOptions::default(),
+            profile: profile.map(str::to_string),
+            timeout_ms: None,
+        }
+    }
+
+    fn open(server: &Server, name: &str, code: &str) -> Result<TwoSlash, Failure> {
+        let (name, code) = (name.to_string(), code.to_string());
+        server.open_session(None, name, code, SnippetOptions::default(), None, None)
+    }
+
+    /// The code of a successful result.
+    fn code(result: Result<TwoSlash, Failure>) -> String {
+        match result {
+            Ok(twoslash_result) => twoslash_result.code,
+            Err(failure) => panic!("{}", failure.message),
+        }
+    }
+
+    /// The kind of a failed result.
+    fn failure_kind(result: Result<TwoSlash, Failure>) -> &'static str {
+        match result {
+            Ok(_) => panic!("expected the request to fail"),
+            Err(failure) => failure.kind,
+        }
+    }
+
+    #[test]
+    fn test_batches_answer_in_order() {
+        let dir = TempDir::new().unwrap();
+        let server = server(dir.path());
+        let snippets = vec![
+            snippet("let a = 1;", None),
+            snippet("let b = 2;", Some("nonexistent")),
+            snippet("let c = 3;", None),
+        ];
+        let outcomes = server.batch(Some("batch"), snippets, None, None);
+        assert_eq!(outcomes.len(), 3);
+        match &outcomes[0] {
+            Outcome::Result(result) => assert_eq!(result["code"], "let a = 1;"),
+            Outcome::Error(error) => panic!("{}", error.message),
+        }
+        match &outcomes[1] {
+            Outcome::Result(_) => panic!("analyzed with a profile that doesn't exist"),
+            Outcome::Error(error) => assert_eq!(error.kind, "unknownProfile"),
+        }
+        match &outcomes[2] {
+            Outcome::Result(result) => assert_eq!(result["code"], "let c = 3;"),
+            Outcome::Error(error) => panic!("{}", error.message),
+        }
+    }
+
+    #[test]
+    fn test_sessions_apply_edits() {
+        let dir = TempDir::new().unwrap();
+        let server = server(dir.path());
+
+        assert_eq!(code(open(&server, "draft", "let x = 1;")), "let x = 1;");
+        assert_eq!(failure_kind(open(&server, "draft", "")), "sessionExists");
+        let result = server.edit_session(None, "draft", &[Edit::new(8, 9, "42")], None);
+        assert_eq!(code(result), "let x = 42;");
+        let result = server.edit_session(None, "draft", &[Edit::new(8, 100, "")], None);
+        assert_eq!(failure_kind(result), "badEdit");
+
+        assert!(server.close_session("draft"));
+        assert!(!server.close_session("draft"));
+        let result = server.edit_session(None, "draft", &[Edit::new(0, 0, "")], None);
+        assert_eq!(failure_kind(result), "unknownSession");
+    }
+
+    #[test]
+    fn test_evicts_the_least_recently_used_session() {
+        let dir = TempDir::new().unwrap();
+        let server = server(dir.path());
+        code(open(&server, "a", "let x = 1;"));
+        code(open(&server, "b", "let x = 1;"));
+        code(server.edit_session(None, "a", &[], None));
+        code(open(&server, "c", "let x = 1;"));
+        assert_eq!(server.status()["sessions"], json!(["a", "c"]));
+    }
+}
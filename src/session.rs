@@ -0,0 +1,163 @@
+//! Documents that clients keep open on a server and edit bit by bit, like an editor would, so
+//! that each edit is analyzed by the same rust-analyzer database as the last.
+
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+
+use crate::cancel::Cancel;
+use crate::options::{Encoding, SnippetOptions};
+use crate::pool::Pool;
+use crate::profiles::Profile;
+use crate::project::ProjectSettings;
+use crate::protocol::Edit;
+use crate::stats::{Phases, Stats};
+use crate::twoslash::TwoSlash;
+
+/// An open document, and the worker that analyzes it.
+pub struct Session {
+    /// The name of the profile the document is analyzed with.
+    pub profile: String,
+    options: SnippetOptions,
+    /// The document as it is now, twoslash markup and all.
+    pub text: String,
+    /// A pool of one worker, with its own project, so that edits to the document are applied to
+    /// the same analysis one after another. `None` once the session is being dropped.
+    pool: Option<Pool>,
+    dir: PathBuf,
+}
+
+impl Session {
+    /// Scaffolds a project for a document in `dir`, with `profile`'s settings.
+    pub fn open(
+        profile: &Profile,
+        options: SnippetOptions,
+        text: String,
+        dir: PathBuf,
+        stats: &Arc<Stats>,
+    ) -> Result<Self> {
+        let settings = ProjectSettings {
+            dir: dir.clone(),
+            ..profile.settings.clone()
+        };
+        let pool = match Pool::new(&settings, 1, stats) {
+            Ok(pool) => pool,
+            Err(e) => {
+                let _ = fs::remove_dir_all(&dir);
+                return Err(e);
+            }
+        };
+        Ok(Session {
+            profile: profile.name.clone(),
+            options: profile.options(options),
+            text,
+            pool: Some(pool),
+            dir,
+        })
+    }
+
+    /// Analyzes the document as it is now.
+    pub fn twoslash(
+        &self,
+        timeout: Option<Duration>,
+        cancel: Cancel,
+    ) -> (Result<TwoSlash>, Phases) {
+        let pool = self.pool.as_ref().unwrap();
+        pool.twoslash(self.text.clone(), self.options.clone(), timeout, cancel)
+    }
+
+    /// Applies `edits` to the document, one after another. If any edit is out of bounds, none of
+    /// them are applied.
+    pub fn edit(&mut self, edits: &[Edit]) -> Result<()> {
+        self.text = apply_edits(&self.text, edits, self.options.encoding)?;
+        Ok(())
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        // Stop the worker before removing its project from under it.
+        drop(self.pool.take());
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// Converts an offset into `text`, measured in `encoding`, into a byte offset. Offsets past the end
+/// or inside a character have none.
+fn byte_offset(text: &str, offset: u32, encoding: Encoding) -> Option<usize> {
+    let offset = offset as usize;
+    match encoding {
+        Encoding::Utf8 => text.is_char_boundary(offset).then_some(offset),
+        Encoding::Utf16 => {
+            let mut units = 0;
+            for (index, c) in text.char_indices() {
+                if units == offset {
+                    return Some(index);
+                }
+                units += c.len_utf16();
+            }
+            (units == offset).then_some(text.len())
+        }
+    }
+}
+
+/// Applies `edits` to `text`, one after another; each edit's range is into the text as the edits
+/// before it left it.
+pub fn apply_edits(text: &str, edits: &[Edit], encoding: Encoding) -> Result<String> {
+    let mut text = text.to_string();
+    for edit in edits {
+        let start = byte_offset(&text, edit.range.start, encoding);
+        let end = byte_offset(&text, edit.range.end, encoding);
+        match (start, end) {
+            (Some(start), Some(end)) if start <= end => text.replace_range(start..end, &edit.text),
+            _ => anyhow::bail!(
+                "edit range {}..{} is not within the document",
+                edit.range.start,
+                edit.range.end
+            ),
+        }
+    }
+    Ok(text)
+}
+
+#[cfg(test)]
+mod test {
+    use super::apply_edits;
+    use crate::options::Encoding;
+    use crate::protocol::Edit;
+
+    #[test]
+    fn test_applies_edits_in_order() {
+        let edits = [Edit::new(8, 9, "42"), Edit::new(0, 0, "// @run\n")];
+        assert_eq!(
+            apply_edits("let x = 1;", &edits, Encoding::Utf8).unwrap(),
+            "// @run\nlet x = 42;"
+        );
+    }
+
+    #[test]
+    fn test_measures_utf16_offsets() {
+        // "é" is two bytes of UTF-8 but one unit of UTF-16, and "🦀" is four bytes but two units.
+        let text = "let é = \"🦀\";";
+        assert_eq!(
+            apply_edits(text, &[Edit::new(11, 11, "!")], Encoding::Utf16).unwrap(),
+            "let é = \"🦀!\";"
+        );
+        assert_eq!(
+            apply_edits(text, &[Edit::new(14, 14, "!")], Encoding::Utf8).unwrap(),
+            "let é = \"🦀!\";"
+        );
+    }
+
+    #[test]
+    fn test_rejects_edits_out_of_bounds() {
+        assert!(apply_edits("abc", &[Edit::new(2, 4, "")], Encoding::Utf8).is_err());
+        assert!(apply_edits("abc", &[Edit::new(2, 1, "")], Encoding::Utf8).is_err());
+        // Inside the two bytes of "é", or the two units of "🦀".
+        assert!(apply_edits("é", &[Edit::new(1, 1, "")], Encoding::Utf8).is_err());
+        assert!(apply_edits("🦀", &[Edit::new(1, 1, "")], Encoding::Utf16).is_err());
+    }
+}
@@ -0,0 +1,224 @@
+//! Statistics about a server, for clients checking on its health.
+
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+/// The steps of analyzing a snippet that we time.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Phase {
+    /// Stripping the twoslash markup (directives, queries and cuts) from the snippet.
+    FindQueries,
+    /// Giving the snippet to rust-analyzer.
+    ApplyChange,
+    Diagnostics,
+    Clippy,
+    Hovers,
+    Queries,
+    /// Building and running the snippet.
+    Execution,
+}
+
+/// How long each phase took while analyzing one snippet, in the order they ran.
+pub type Phases = Vec<(Phase, Duration)>;
+
+/// Records `Phases` as a snippet is analyzed.
+#[derive(Default)]
+pub struct Timings(RefCell<Phases>);
+
+impl Timings {
+    /// Runs `f`, recording how long it took as `phase`.
+    pub fn time<T>(&self, phase: Phase, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = f();
+        self.0.borrow_mut().push((phase, start.elapsed()));
+        result
+    }
+
+    pub fn phases(&self) -> Phases {
+        self.0.borrow().clone()
+    }
+}
+
+/// A duration in milliseconds, to the microsecond.
+pub fn millis(duration: Duration) -> f64 {
+    duration.as_micros() as f64 / 1000.0
+}
+
+/// How many of the latest samples percentiles are computed over.
+const SAMPLES: usize = 1000;
+
+#[derive(Default)]
+struct Samples(VecDeque<Duration>);
+
+impl Samples {
+    fn push(&mut self, sample: Duration) {
+        if self.0.len() == SAMPLES {
+            self.0.pop_front();
+        }
+        self.0.push_back(sample);
+    }
+
+    fn latency(&self) -> Latency {
+        let mut sorted: Vec<_> = self.0.iter().copied().collect();
+        sorted.sort_unstable();
+        let percentile = |p: usize| match sorted.len() {
+            0 => 0.0,
+            n => millis(sorted[(n - 1) * p / 100]),
+        };
+        Latency {
+            samples: sorted.len(),
+            p50_ms: percentile(50),
+            p95_ms: percentile(95),
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Latency {
+    samples: usize,
+    p50_ms: f64,
+    p95_ms: f64,
+}
+
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LastError {
+    kind: String,
+    message: String,
+    /// When it happened, in seconds since the Unix epoch.
+    at: u64,
+}
+
+#[derive(Default)]
+struct State {
+    requests: u64,
+    failures: u64,
+    cache_hits: u64,
+    total: Samples,
+    phases: HashMap<Phase, Samples>,
+    last_error: Option<LastError>,
+}
+
+/// Statistics about the requests a server has handled. Shared between everything handling them.
+pub struct Stats {
+    started: Instant,
+    state: Mutex<State>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Snapshot {
+    uptime_secs: u64,
+    requests: u64,
+    failures: u64,
+    /// How many requests were answered from the cache, without analysis.
+    cache_hits: u64,
+    /// End-to-end latency of requests, including waiting for a free worker.
+    latency: Latency,
+    phases: HashMap<Phase, Latency>,
+    /// Resident memory of the server process, if it is known.
+    memory_bytes: Option<u64>,
+    last_error: Option<LastError>,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Stats {
+            started: Instant::now(),
+            state: Mutex::new(State::default()),
+        }
+    }
+}
+
+impl Stats {
+    pub fn record_phases(&self, timings: &Timings) {
+        let mut state = self.state.lock().unwrap();
+        for (phase, duration) in timings.0.borrow().iter() {
+            state.phases.entry(*phase).or_default().push(*duration);
+        }
+    }
+
+    /// Records a request that took `duration`, and its error if it failed.
+    pub fn record_request(&self, duration: Duration, error: Option<(&str, &str)>) {
+        let mut state = self.state.lock().unwrap();
+        state.requests += 1;
+        state.total.push(duration);
+        if let Some((kind, message)) = error {
+            state.failures += 1;
+            state.last_error = Some(LastError {
+                kind: kind.to_string(),
+                message: message.to_string(),
+                at: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |since| since.as_secs()),
+            });
+        }
+    }
+
+    pub fn record_cache_hit(&self) {
+        self.state.lock().unwrap().cache_hits += 1;
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        let state = self.state.lock().unwrap();
+        Snapshot {
+            uptime_secs: self.started.elapsed().as_secs(),
+            requests: state.requests,
+            failures: state.failures,
+            cache_hits: state.cache_hits,
+            latency: state.total.latency(),
+            phases: state
+                .phases
+                .iter()
+                .map(|(phase, samples)| (*phase, samples.latency()))
+                .collect(),
+            memory_bytes: resident_memory(),
+            last_error: state.last_error.clone(),
+        }
+    }
+}
+
+/// The resident set size of this process, from `/proc` where there is one.
+fn resident_memory() -> Option<u64> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+    let kib: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kib * 1024)
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{Samples, SAMPLES};
+
+    #[test]
+    fn test_percentiles() {
+        let mut samples = Samples::default();
+        assert_eq!(samples.latency().samples, 0);
+        for ms in 1..=100 {
+            samples.push(Duration::from_millis(ms));
+        }
+        let latency = samples.latency();
+        assert_eq!(latency.samples, 100);
+        assert_eq!(latency.p50_ms, 50.0);
+        assert_eq!(latency.p95_ms, 95.0);
+    }
+
+    #[test]
+    fn test_keeps_latest_samples() {
+        let mut samples = Samples::default();
+        for ms in 0..(SAMPLES as u64 + 10) {
+            samples.push(Duration::from_millis(ms));
+        }
+        assert_eq!(samples.0.len(), SAMPLES);
+        assert_eq!(samples.0[0], Duration::from_millis(10));
+    }
+}
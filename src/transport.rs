@@ -0,0 +1,259 @@
+//! Carries frames between clients and a `Server`.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::Result;
+use serde_json::json;
+
+use crate::http;
+use crate::logging;
+use crate::protocol::{self, FrameError};
+use crate::server::{Reply, Server};
+
+/// Serves frames from one connection until the client hangs up or asks the server to shut down.
+/// Requests are answered in the order they arrive, so clients may pipeline several requests and
+/// match up the responses by id. That goes for cancel requests too, which therefore have to come
+/// over another connection to cancel anything. Returns whether the client asked the server to shut down.
+/// Malformed frames are answered with an error; if we can't tell where the next frame starts, we
+/// hang up after answering.
+pub fn serve_connection(server: &Server, mut reader: impl Read, mut writer: impl Write) -> bool {
+    loop {
+        let frame = match protocol::read(&mut reader) {
+            Ok(frame) => frame,
+            // The client hung up, or the connection broke.
+            Err(_) => return false,
+        };
+        let (reply, resumable) = match frame.payload {
+            Ok(payload) => (server.handle(payload), true),
+            Err(e) => {
+                logging::warn(
+                    "malformed frame",
+                    json!({ "kind": e.kind(), "error": e.to_string() }),
+                );
+                (Reply::error(None, e.kind(), &e), e.resumable())
+            }
+        };
+
+        if let Some(payload) = &reply.frame {
+            let written = match protocol::write(&mut writer, frame.framing, payload) {
+                // The other side would reject a response this large, so tell it why instead.
+                Err(e) if e.is::<FrameError>() => {
+                    logging::warn("response too large", json!({ "error": e.to_string() }));
+                    let error = Reply::error(response_id(payload), "frameTooLarge", e);
+                    let error = error.frame.unwrap_or_default();
+                    protocol::write(&mut writer, frame.framing, &error)
+                }
+                written => written,
+            };
+            if written.and_then(|()| Ok(writer.flush()?)).is_err() {
+                return reply.shutdown;
+            }
+        }
+        if reply.shutdown {
+            return true;
+        }
+        if !resumable {
+            return false;
+        }
+    }
+}
+
+/// The id of a response, so that it can be replaced with an error for the same request.
+fn response_id(payload: &str) -> Option<String> {
+    let response: serde_json::Value = serde_json::from_str(payload).ok()?;
+    response["id"].as_str().map(str::to_string)
+}
+
+/// How often a listening server checks whether it should shut itself down; see `Server::expired`.
+const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A socket that clients connect to.
+trait Listener: Sync {
+    type Stream: Send + Sync;
+
+    fn accept(&self) -> io::Result<Self::Stream>;
+    fn try_clone(stream: &Self::Stream) -> io::Result<Self::Stream>;
+    fn hang_up(stream: &Self::Stream);
+    /// Connects to the listener, to wake up a blocked `accept`.
+    fn wake(&self);
+}
+
+impl Listener for TcpListener {
+    type Stream = TcpStream;
+
+    fn accept(&self) -> io::Result<TcpStream> {
+        TcpListener::accept(self).map(|(stream, _)| stream)
+    }
+
+    fn try_clone(stream: &TcpStream) -> io::Result<TcpStream> {
+        stream.try_clone()
+    }
+
+    fn hang_up(stream: &TcpStream) {
+        let _ = stream.shutdown(Shutdown::Both);
+    }
+
+    fn wake(&self) {
+        if let Ok(addr) = self.local_addr() {
+            let _ = TcpStream::connect(addr);
+        }
+    }
+}
+
+impl Listener for UnixListener {
+    type Stream = UnixStream;
+
+    fn accept(&self) -> io::Result<UnixStream> {
+        UnixListener::accept(self).map(|(stream, _)| stream)
+    }
+
+    fn try_clone(stream: &UnixStream) -> io::Result<UnixStream> {
+        stream.try_clone()
+    }
+
+    fn hang_up(stream: &UnixStream) {
+        let _ = stream.shutdown(Shutdown::Both);
+    }
+
+    fn wake(&self) {
+        if let Some(path) = self
+            .local_addr()
+            .ok()
+            .and_then(|addr| addr.as_pathname().map(Path::to_path_buf))
+        {
+            let _ = UnixStream::connect(path);
+        }
+    }
+}
+
+/// Serves clients connecting to `listener`, each on its own thread with `serve`, until one of them
+/// asks the server to shut down, or it has been idle or orphaned for too long. Requests from
+/// different connections are analyzed concurrently, as far as the server's worker pool allows.
+fn serve_listener<L, S>(server: Server, listener: L, serve: S) -> Result<()>
+where
+    L: Listener,
+    for<'a> &'a L::Stream: Read + Write,
+    S: Fn(&Server, &L::Stream, &L::Stream) -> bool + Sync,
+{
+    let shutdown = AtomicBool::new(false);
+    // Handles on open connections, so we can hang up on them when shutting down.
+    let connections = Mutex::new(HashMap::<u64, L::Stream>::new());
+    let next_connection = AtomicU64::new(0);
+
+    thread::scope(|scope| {
+        // Shuts the server down once it has no reason to keep running.
+        scope.spawn(|| {
+            while !shutdown.load(Ordering::SeqCst) {
+                thread::sleep(WATCH_INTERVAL);
+                if let Some(reason) = server.expired() {
+                    logging::info("shutting down", json!({ "reason": reason }));
+                    shutdown.store(true, Ordering::SeqCst);
+                    listener.wake();
+                }
+            }
+        });
+
+        loop {
+            let stream = listener.accept();
+            if shutdown.load(Ordering::SeqCst) {
+                break;
+            }
+            // Problems with one connection are that connection's problem, not the server's.
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(_) => continue,
+            };
+            let id = next_connection.fetch_add(1, Ordering::SeqCst);
+            if let Ok(handle) = L::try_clone(&stream) {
+                connections.lock().unwrap().insert(id, handle);
+            }
+
+            let (server, shutdown, connections, listener, serve) =
+                (&server, &shutdown, &connections, &listener, &serve);
+            scope.spawn(move || {
+                logging::debug("connection opened", json!({ "connection": id }));
+                let asked_to_shut_down = serve(server, &stream, &stream);
+                logging::debug("connection closed", json!({ "connection": id }));
+                if asked_to_shut_down {
+                    logging::info("shutting down", json!({ "reason": "a client asked" }));
+                    shutdown.store(true, Ordering::SeqCst);
+                    // Wake up the accept loop so that it notices.
+                    listener.wake();
+                }
+                connections.lock().unwrap().remove(&id);
+            });
+        }
+
+        // Hang up on everyone else, so that their connection threads finish.
+        for stream in connections.lock().unwrap().values() {
+            L::hang_up(stream);
+        }
+    });
+
+    Ok(())
+}
+
+/// Serves clients connecting over TCP. Anyone who can reach the address can use the server.
+pub fn serve_tcp(server: Server, listener: TcpListener) -> Result<()> {
+    serve_listener(server, listener, |server, reader, writer| {
+        serve_connection(server, reader, writer)
+    })
+}
+
+/// Serves HTTP clients connecting over TCP; see `http`.
+pub fn serve_http(server: Server, listener: TcpListener) -> Result<()> {
+    serve_listener(server, listener, |server, reader, writer| {
+        http::serve_connection(server, reader, writer)
+    })
+}
+
+/// Creates a Unix socket at `path` that only its owner may connect to. Fails if something is
+/// already at `path`.
+///
+/// The socket is made in a fresh directory that only we can enter, and only linked into place
+/// once its permissions are set, so that nobody can connect in between even when `path` is in a
+/// shared directory.
+pub fn bind_unix(path: &Path) -> Result<UnixListener> {
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let private = tempfile::Builder::new()
+        .prefix(".twoslash")
+        .tempdir_in(parent)?;
+    let unlinked = private.path().join("twoslash.sock");
+    let listener = UnixListener::bind(&unlinked)?;
+    fs::set_permissions(&unlinked, fs::Permissions::from_mode(0o600))?;
+    fs::hard_link(&unlinked, path)?;
+    Ok(listener)
+}
+
+/// Serves clients connecting to a Unix socket at `path`, which only its owner may connect to; see
+/// `bind_unix`. The socket is removed when the server shuts down.
+pub fn serve_unix(server: Server, listener: UnixListener, path: &Path) -> Result<()> {
+    let result = serve_listener(server, listener, |server, reader, writer| {
+        serve_connection(server, reader, writer)
+    });
+    let _ = fs::remove_file(path);
+    result
+}
+
+/// Serves a single client over stdin and stdout, for servers embedded as a child process. The
+/// server shuts down when the client asks it to, or closes stdin, which it does when it exits; so
+/// neither the idle timeout nor the parent watchdog applies, and `main` refuses both.
+pub fn serve_stdio(server: Server) -> Result<()> {
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+    serve_connection(&server, stdin.lock(), stdout.lock());
+    Ok(())
+}
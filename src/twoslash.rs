@@ -1,7 +1,7 @@
 use ra_ide::Severity;
 use serde::Serialize;
 
-#[derive(Serialize)]
+#[derive(Clone, Serialize)]
 pub struct Highlight {
     kind: String,
     /// The index of the text in the file
@@ -17,7 +17,7 @@ pub struct Highlight {
     length: u32,
 }
 
-#[derive(Serialize)]
+#[derive(Clone, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct StaticQuickInfo {
     /// The string content of the node this represents (mainly for debugging)
@@ -45,12 +45,12 @@ pub enum QueryKind {
     Completions,
 }
 
-#[derive(Serialize)]
+#[derive(Clone, Serialize)]
 pub struct CompletionEntry {
     pub name: String,
 }
 
-#[derive(Serialize)]
+#[derive(Clone, Serialize)]
 pub struct Query {
     pub kind: QueryKind,
     /// What line is the highlighted identifier on?
@@ -74,23 +74,21 @@ pub struct Query {
     pub completions_prefix: Option<String>,
 }
 
-#[derive(Serialize)]
+#[derive(Clone, Serialize)]
 pub struct Tag {
     /// What was the name of the tag
-    name: String,
+    pub name: String,
     /// Where was it located in the original source file
-    line: u32,
+    pub line: u32,
     /// What was the text after the `// @tag: ` string  (optional because you could do // @tag on it's own line without the ':')
     #[serde(skip_serializing_if = "Option::is_none")]
-    annotation: Option<String>,
+    pub annotation: Option<String>,
 }
 
-#[derive(Serialize)]
+#[derive(Clone, Serialize)]
 pub enum DiagnosticCategory {
-    #[allow(unused)]
     Debug = 0,
     Info = 1,
-    #[allow(unused)]
     Warning = 2,
     Error = 3,
 }
@@ -104,7 +102,19 @@ impl From<Severity> for DiagnosticCategory {
     }
 }
 
-#[derive(Serialize)]
+impl DiagnosticCategory {
+    /// The category of a rustc diagnostic with the given `level`.
+    pub fn from_rustc_level(level: &str) -> Self {
+        match level {
+            "error" | "error: internal compiler error" => DiagnosticCategory::Error,
+            "warning" => DiagnosticCategory::Warning,
+            "note" | "help" => DiagnosticCategory::Info,
+            _ => DiagnosticCategory::Debug,
+        }
+    }
+}
+
+#[derive(Clone, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Error {
     pub rendered_message: String,
@@ -117,7 +127,56 @@ pub struct Error {
     pub character: u32,
 }
 
-#[derive(Serialize)]
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Execution {
+    /// Did the snippet build? If not, nothing was run, and `errors` (or `reason`) says why
+    pub compiled: bool,
+    /// Why the snippet was not built at all
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    /// Compiler errors from building the snippet
+    pub errors: Vec<Error>,
+    pub stdout: String,
+    pub stderr: String,
+    /// The exit code of the process, if it exited normally
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exit_code: Option<i32>,
+    /// The message the snippet panicked with, if it panicked
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub panic: Option<String>,
+    /// Was the snippet killed for running too long?
+    pub timed_out: bool,
+    /// Was stdout or stderr cut off because it was too long?
+    pub truncated: bool,
+}
+
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Expectation {
+    /// The rustdoc code block attributes the snippet was checked against
+    pub attributes: String,
+    /// Did the snippet behave as its attributes say it should? Absent if it was not checked
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub held: Option<bool>,
+    /// Why the expectation did not hold, or why it could not be checked
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Clippy {
+    /// The lint groups (or lints) that were enabled, besides clippy's defaults
+    pub lints: Vec<String>,
+    /// Did clippy run? Its lints are among the errors, as warnings
+    pub ran: bool,
+    /// Why clippy did not run
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+#[derive(Clone, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct TwoSlash {
     pub code: String,
@@ -135,4 +194,60 @@ pub struct TwoSlash {
     /// The URL for this sample in the playground
     #[serde(rename = "playgroundURL")]
     pub playground_url: String,
+    /// Whether clippy linted the snippet, if it was asked to with `// @clippy`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub clippy: Option<Clippy>,
+    /// The result of running the snippet, if it was asked to be run with `// @run`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub execution: Option<Execution>,
+    /// Whether the snippet behaved as its rustdoc attributes (given with `// @rustdoc`) expect
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expectation: Option<Expectation>,
+}
+
+/// Converts a span given by UTF-8 `start` and `length` offsets into `code`, which starts at
+/// `character` on its line, into UTF-16 offsets.
+fn utf16_span(code: &str, start: &mut u32, length: &mut u32, character: &mut u32) {
+    let utf16_len =
+        |from: usize, to: usize| code.get(from..to).map(|s| s.encode_utf16().count() as u32);
+    let (from, to) = (*start as usize, (*start + *length) as usize);
+    let line_start = from.saturating_sub(*character as usize);
+    if let (Some(s), Some(l), Some(c)) = (
+        utf16_len(0, from),
+        utf16_len(from, to),
+        utf16_len(line_start, from),
+    ) {
+        *start = s;
+        *length = l;
+        *character = c;
+    }
+}
+
+impl TwoSlash {
+    /// Measures all offsets in UTF-16 code units rather than UTF-8 bytes, like JavaScript does.
+    pub fn with_utf16_offsets(mut self) -> Self {
+        let code = &self.code;
+        for h in self.highlights.iter_mut() {
+            utf16_span(code, &mut h.start, &mut h.length, &mut h.offset);
+        }
+        for info in self.static_quick_infos.iter_mut() {
+            utf16_span(code, &mut info.start, &mut info.length, &mut info.character);
+        }
+        for query in self.queries.iter_mut() {
+            utf16_span(code, &mut query.start, &mut query.length, &mut query.offset);
+        }
+        let execution_errors = self
+            .execution
+            .iter_mut()
+            .flat_map(|execution| execution.errors.iter_mut());
+        for error in self.errors.iter_mut().chain(execution_errors) {
+            utf16_span(
+                code,
+                &mut error.start,
+                &mut error.length,
+                &mut error.character,
+            );
+        }
+        self
+    }
 }
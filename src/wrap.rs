@@ -0,0 +1,173 @@
+//! Wraps statement-level snippets in `fn main`, like rustdoc does for doctests.
+
+use std::collections::HashSet;
+
+use ra_syntax::ast::{self, HasAttrs, HasName};
+use ra_syntax::{AstNode, SourceFile, TextRange};
+
+/// Where the lines of a snippet ended up after it was wrapped.
+pub struct LineMap {
+    /// For each line of the wrapped code, the line of the snippet it came from, or `None` if it
+    /// was added by the wrapping.
+    original_lines: Vec<Option<u32>>,
+}
+
+impl LineMap {
+    /// The line of the snippet that `line` of the wrapped code came from.
+    pub fn to_original(&self, line: u32) -> Option<u32> {
+        self.original_lines.get(line as usize).copied().flatten()
+    }
+
+    /// The line of the wrapped code that `line` of the snippet ended up on, or `None` if the
+    /// snippet has no such line, like the empty one after a trailing newline.
+    pub fn to_wrapped(&self, line: u32) -> Option<u32> {
+        self.original_lines
+            .iter()
+            .position(|&original| original == Some(line))
+            .map(|wrapped| wrapped as u32)
+    }
+}
+
+pub struct Wrapped {
+    pub code: String,
+    pub lines: LineMap,
+}
+
+/// Returns the lines spanned by `range` in `src`.
+fn lines_of(src: &str, range: TextRange) -> std::ops::RangeInclusive<u32> {
+    let line_of = |offset: usize| src[..offset].matches('\n').count() as u32;
+    line_of(range.start().into())..=line_of(range.end().into())
+}
+
+fn wrap(src: &str, file: &SourceFile) -> Wrapped {
+    // Crate attributes, `extern crate`s and imports stay at the top level; everything else goes
+    // in `fn main`.
+    let hoisted_ranges = file
+        .attrs()
+        .filter(|attr| attr.excl_token().is_some())
+        .map(|attr| attr.syntax().text_range())
+        .chain(file.items().filter_map(|item| match item {
+            ast::Item::Use(_) | ast::Item::ExternCrate(_) => Some(item.syntax().text_range()),
+            _ => None,
+        }));
+    let hoisted: HashSet<u32> = hoisted_ranges
+        .flat_map(|range| lines_of(src, range))
+        .collect();
+
+    let mut code = vec![];
+    let mut original_lines = vec![];
+    let lines: Vec<_> = src
+        .lines()
+        .enumerate()
+        .map(|(i, l)| (i as u32, l))
+        .collect();
+    for &(i, line) in lines.iter().filter(|(i, _)| hoisted.contains(i)) {
+        code.push(line);
+        original_lines.push(Some(i));
+    }
+    code.push("fn main() {");
+    original_lines.push(None);
+    for &(i, line) in lines.iter().filter(|(i, _)| !hoisted.contains(i)) {
+        code.push(line);
+        original_lines.push(Some(i));
+    }
+    code.push("}");
+    original_lines.push(None);
+
+    Wrapped {
+        code: code.join("\n"),
+        lines: LineMap { original_lines },
+    }
+}
+
+/// Whether a top-level macro call is a statement, like `println!("hi");`, rather than one that
+/// defines items, like `thread_local! { ... }`. Both parse as items, so tell them apart the way
+/// they are conventionally written: statements with parentheses or brackets, items with braces.
+fn is_statement_macro(call: &ast::MacroCall) -> bool {
+    call.token_tree()
+        .is_some_and(|tt| tt.l_curly_token().is_none())
+}
+
+/// Wraps `src` in `fn main` if it is made of statements rather than items, or returns `None` if
+/// it should be analyzed as-is.
+pub fn wrap_main(src: &str) -> Option<Wrapped> {
+    let parse = SourceFile::parse(src);
+    let errors = parse.errors().len();
+    let file = parse.tree();
+    let has_main = file.items().any(|item| match item {
+        ast::Item::Fn(f) => f.name().is_some_and(|name| name.to_string() == "main"),
+        _ => false,
+    });
+    if has_main {
+        return None;
+    }
+    let has_statement_macros = file.items().any(|item| match item {
+        ast::Item::MacroCall(call) => is_statement_macro(&call),
+        _ => false,
+    });
+    if errors == 0 && !has_statement_macros {
+        return None;
+    }
+
+    // Only keep the wrapping if it makes more sense of the snippet than before. Statement macros
+    // make sense either way, but only run inside a function.
+    let wrapped = wrap(src, &file);
+    let wrapped_errors = SourceFile::parse(&wrapped.code).errors().len();
+    match wrapped_errors < errors || (has_statement_macros && wrapped_errors <= errors) {
+        true => Some(wrapped),
+        false => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::wrap_main;
+
+    #[test]
+    fn test_wrap_statements() {
+        let src = r#"
+use std::collections::HashMap;
+let mut m = HashMap::new();
+m.insert(1, 2);
+"#
+        .trim();
+        let wrapped = wrap_main(src).unwrap();
+
+        assert_eq!(
+            wrapped.code,
+            r#"
+use std::collections::HashMap;
+fn main() {
+let mut m = HashMap::new();
+m.insert(1, 2);
+}
+"#
+            .trim()
+        );
+        assert_eq!(wrapped.lines.to_original(0), Some(0));
+        assert_eq!(wrapped.lines.to_original(1), None);
+        assert_eq!(wrapped.lines.to_original(2), Some(1));
+        assert_eq!(wrapped.lines.to_wrapped(2), Some(3));
+        assert_eq!(wrapped.lines.to_wrapped(3), None);
+    }
+
+    #[test]
+    fn test_wrap_macro_statements() {
+        let wrapped = wrap_main(
+            "assert_eq!(1 + 1, 2);
+println!(\"hi\");",
+        )
+        .unwrap();
+        assert_eq!(
+            wrapped.code,
+            "fn main() {\nassert_eq!(1 + 1, 2);\nprintln!(\"hi\");\n}"
+        );
+        assert!(wrap_main("thread_local! { static X: u8 = 1; }").is_none());
+    }
+
+    #[test]
+    fn test_items_are_not_wrapped() {
+        assert!(wrap_main("fn foo() -> bool { 1 }").is_none());
+        assert!(wrap_main("fn main() { let x = 1; }\nlet y = 2;").is_none());
+    }
+}